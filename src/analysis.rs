@@ -0,0 +1,192 @@
+use log::debug;
+
+use crate::matrix::Matrix;
+use crate::staves::{Tolerances, TrackerConfig};
+
+/**
+Vertical run-length histograms of a binarized column-major buffer, indexed by run length.
+White runs touching the top or bottom of a column are not counted.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunHistograms {
+    pub black: Vec<usize>,
+    pub white: Vec<usize>
+}
+
+/**
+staffline_height : Most frequent black run length, the thickness of a staff line.
+staffspace_height : Most frequent white run length, the gap between two staff lines.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageMetrics {
+    pub staffline_height: usize,
+    pub staffspace_height: usize
+}
+
+impl PageMetrics {
+
+    /**
+    Distance between the centres of two consecutive staff lines.
+    */
+    pub fn staff_spacing(&self) -> usize {
+        self.staffline_height + self.staffspace_height
+    }
+
+    /**
+    Half a line thickness, plus the 0.9 pixel drift allowed for a 1 pixel line,
+    in standard deviations of the centre measurement of `tracker_config`.
+    */
+    pub fn match_gate(&self) -> f32 {
+        (self.staffline_height as f32 / 2.0 + 0.9) / self.centre_deviation()
+    }
+
+    fn centre_deviation(&self) -> f32 {
+        (self.staffline_height as f32 / 2.0).max(1.0)
+    }
+
+    pub fn max_run_height(&self) -> usize {
+        2 * self.staffline_height + 1
+    }
+
+    pub fn gap_tolerance(&self) -> usize {
+        2 * self.staff_spacing()
+    }
+
+    pub fn tolerances(&self) -> Tolerances {
+        Tolerances {
+            gate: self.match_gate(),
+            max_run_height: self.max_run_height(),
+            max_gap: self.gap_tolerance()
+        }
+    }
+
+    /**
+    Tracker parameters scaled to the page resolution: the centre of a thick line is measured
+    within half its thickness, and a line slope may drift by one pixel over a staff spacing.
+    A line is confirmed over a staff spacing, and kept when longer than two.
+    */
+    pub fn tracker_config(&self) -> TrackerConfig {
+        let half_line = self.centre_deviation();
+        let drift = 1.0 / self.staff_spacing().max(1) as f32;
+
+        TrackerConfig {
+            q: Matrix::diagonal([0.0, drift * drift, 0.0]),
+            r: Matrix::diagonal([half_line * half_line, 1.0]),
+            tolerances: self.tolerances(),
+            confirm_length: self.staff_spacing(),
+            min_length: 2 * self.staff_spacing(),
+            ..TrackerConfig::default()
+        }
+    }
+
+}
+
+pub fn run_histograms(buffer_vertical: &[u8], height: usize) -> RunHistograms {
+    let mut histograms = RunHistograms {
+        black: vec![0; height + 1],
+        white: vec![0; height + 1]
+    };
+
+    if height == 0 {return histograms;}
+
+    for column in buffer_vertical.chunks(height) {
+        let mut start = 0;
+        for x in 1..=column.len() {
+            if x < column.len() && (column[x] == 0) == (column[start] == 0) {continue;}
+
+            let len = x - start;
+            if column[start] == 0 {
+                histograms.black[len] += 1;
+            } else if start > 0 && x < column.len() {
+                histograms.white[len] += 1;
+            }
+            start = x;
+        }
+    }
+
+    histograms
+}
+
+/**
+Returns None when the buffer has no black run or no white run between two black ones.
+*/
+pub fn analyse(buffer_vertical: &[u8], height: usize) -> Option<PageMetrics> {
+    let histograms = run_histograms(buffer_vertical, height);
+
+    let metrics = PageMetrics {
+        staffline_height: mode(&histograms.black)?,
+        staffspace_height: mode(&histograms.white)?
+    };
+
+    debug!("Page metrics:{:?}", metrics);
+
+    Some(metrics)
+}
+
+fn mode(histogram: &[usize]) -> Option<usize> {
+    histogram
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .max_by_key(|(len, count)| (**count, std::cmp::Reverse(*len)))
+        .map(|(len, _)| len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_histograms() {
+        let column = vec![255, 0, 0, 255, 255, 255, 0, 255];
+        let histograms = run_histograms(&column, 8);
+
+        assert_eq!(histograms.black, vec![0, 1, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(histograms.white, vec![0, 0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_run_histograms_split_columns() {
+        let buffer = vec![0, 255, 0, 0, 255, 0];
+        let histograms = run_histograms(&buffer, 3);
+
+        assert_eq!(histograms.black, vec![0, 4, 0, 0]);
+        assert_eq!(histograms.white, vec![0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_analyse_staff() {
+        let height = 40;
+        let mut buffer = vec![255; 20 * height];
+        for c in 0..20 {
+            for line in 0..5 {
+                let row = 5 + line * 7;
+                buffer[c * height + row] = 0;
+                buffer[c * height + row + 1] = 0;
+            }
+        }
+
+        let metrics = analyse(&buffer, height).unwrap();
+
+        assert_eq!(metrics, PageMetrics { staffline_height: 2, staffspace_height: 5 });
+        assert_eq!(metrics.staff_spacing(), 7);
+        assert_eq!(metrics.tolerances(), Tolerances { gate: 1.9, max_run_height: 5, max_gap: 14 });
+
+        let config = metrics.tracker_config();
+        assert_eq!(config.tolerances, metrics.tolerances());
+        assert_eq!(config.r, Matrix::identity());
+        assert!((config.q.0[1][1] - 1.0 / 49.0).abs() < 1e-6);
+        assert_eq!((config.confirm_length, config.min_length), (7, 14));
+    }
+
+    #[test]
+    fn test_analyse_without_ink() {
+        assert_eq!(analyse(&[255; 10], 5), None);
+    }
+
+    #[test]
+    fn test_one_pixel_line_keep_default_gate() {
+        let metrics = PageMetrics { staffline_height: 1, staffspace_height: 6 };
+        assert!((metrics.match_gate() - Tolerances::default().gate).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,168 @@
+use log::trace;
+
+// Stands for a forbidden pairing in the square cost matrix given to the Hungarian algorithm.
+const FORBIDDEN: f64 = 1e9;
+
+/**
+Optimal assignment of rows to columns of a rectangular cost matrix.
+
+costs : The cost of every row and column pairing, None when the pair is gated out.
+birth : The cost of leaving a row unassigned.
+miss : The cost of leaving a column unassigned.
+
+Returns the column assigned to each row, None for a row left unassigned.
+*/
+pub fn assign(costs: &[Vec<Option<f32>>], birth: f32, miss: f32) -> Vec<Option<usize>> {
+    let rows = (0..costs.len())
+        .filter(|r| costs[*r].iter().any(|c| c.is_some()))
+        .collect::<Vec<usize>>();
+    let columns = (0..costs.first().map_or(0, |row| row.len()))
+        .filter(|c| costs.iter().any(|row| row[*c].is_some()))
+        .collect::<Vec<usize>>();
+
+    let size = rows.len() + columns.len();
+    let mut square = vec![vec![FORBIDDEN; size]; size];
+
+    for (i, r) in rows.iter().enumerate() {
+        for (j, c) in columns.iter().enumerate() {
+            if let Some(cost) = costs[*r][*c] {
+                square[i][j] = cost as f64;
+            }
+        }
+        square[i][columns.len() + i] = birth as f64;
+    }
+    for j in 0..columns.len() {
+        square[rows.len() + j][j] = miss as f64;
+        square[rows.len() + j][columns.len()..].iter_mut().for_each(|v| *v = 0.0);
+    }
+
+    let solution = hungarian(&square);
+
+    trace!("Assign rows:{:?} to columns:{:?} give:{:?}", rows, columns, solution);
+
+    let mut result = vec![None; costs.len()];
+    for (i, r) in rows.iter().enumerate() {
+        if solution[i] < columns.len() && square[i][solution[i]] < FORBIDDEN {
+            result[*r] = Some(columns[solution[i]]);
+        }
+    }
+    result
+}
+
+/**
+Hungarian algorithm with potentials on a square cost matrix, in O(n³).
+
+Returns the column assigned to each row.
+*/
+pub fn hungarian(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    let mut u = vec![0.0; n + 1];
+    let mut v = vec![0.0; n + 1];
+    // Row matched to each column, 1-based, 0 for none.
+    let mut matched = vec![0; n + 1];
+    let mut way = vec![0; n + 1];
+
+    for row in 1..=n {
+        matched[0] = row;
+        let mut j0 = 0;
+        let mut min_v = vec![f64::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+
+        loop {
+            used[j0] = true;
+            let i0 = matched[j0];
+            let mut delta = f64::INFINITY;
+            let mut j1 = 0;
+
+            for j in 1..=n {
+                if used[j] {continue;}
+                let cur = costs[i0 - 1][j - 1] - u[i0] - v[j];
+                if cur < min_v[j] {
+                    min_v[j] = cur;
+                    way[j] = j0;
+                }
+                if min_v[j] < delta {
+                    delta = min_v[j];
+                    j1 = j;
+                }
+            }
+
+            for j in 0..=n {
+                if used[j] {
+                    u[matched[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_v[j] -= delta;
+                }
+            }
+
+            j0 = j1;
+            if matched[j0] == 0 {break;}
+        }
+
+        while j0 != 0 {
+            let j1 = way[j0];
+            matched[j0] = matched[j1];
+            j0 = j1;
+        }
+    }
+
+    let mut result = vec![0; n];
+    for j in 1..=n {
+        if matched[j] != 0 {
+            result[matched[j] - 1] = j - 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hungarian() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0]
+        ];
+        assert_eq!(hungarian(&costs), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_hungarian_empty() {
+        assert_eq!(hungarian(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_assign_beat_greedy_choice() {
+        // Greedily, row 0 would take column 0 and leave row 1 unassigned.
+        let costs = vec![
+            vec![Some(1.0), Some(2.0)],
+            vec![Some(1.5), None]
+        ];
+        assert_eq!(assign(&costs, 10.0, 10.0), vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn test_assign_give_birth_when_cheaper() {
+        let costs = vec![
+            vec![Some(1.0)],
+            vec![Some(8.0)]
+        ];
+        assert_eq!(assign(&costs, 3.0, 3.0), vec![Some(0), None]);
+        assert_eq!(assign(&costs, 3.0, 6.0), vec![Some(0), None]);
+        assert_eq!(assign(&[vec![Some(5.0)]], 2.0, 2.0), vec![None]);
+    }
+
+    #[test]
+    fn test_assign_gated_rows_and_columns() {
+        let costs = vec![
+            vec![None, None, None],
+            vec![None, Some(0.5), None]
+        ];
+        assert_eq!(assign(&costs, 1.0, 1.0), vec![None, Some(1)]);
+        assert_eq!(assign(&[], 1.0, 1.0), Vec::<Option<usize>>::new());
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::staves::Staff;
+use crate::systems::StaffGroup;
+
+/**
+Single : One thin stroke.
+Double : Two thin strokes.
+Final : A thin and a thick stroke, or any barline holding a thick stroke without repeat dots.
+RepeatStart : Repeat dots on the right of the strokes.
+RepeatEnd : Repeat dots on the left of the strokes.
+RepeatBoth : Repeat dots on both sides of the strokes.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BarlineKind {
+    Single,
+    Double,
+    Final,
+    RepeatStart,
+    RepeatEnd,
+    RepeatBoth
+}
+
+impl std::fmt::Display for BarlineKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BarlineKind::Single => write!(f, "single"),
+            BarlineKind::Double => write!(f, "double"),
+            BarlineKind::Final => write!(f, "final"),
+            BarlineKind::RepeatStart => write!(f, "repeat start"),
+            BarlineKind::RepeatEnd => write!(f, "repeat end"),
+            BarlineKind::RepeatBoth => write!(f, "repeat both")
+        }
+    }
+}
+
+/**
+A barline crossing one staff, or several connected staves for a grand staff or a system.
+
+kind : The barline kind, see `BarlineKind`.
+start, end : First and last 1-based column of its strokes, repeat dots left out.
+strokes : First and last column of every stroke, from left to right.
+first_staff, last_staff : Indices in the groups given to `detect_barlines` of the top and bottom staves it crosses.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Barline {
+    pub kind: BarlineKind,
+    pub start: usize,
+    pub end: usize,
+    pub strokes: Vec<(usize, usize)>,
+    pub first_staff: usize,
+    pub last_staff: usize
+}
+
+impl Barline {
+
+    /**
+    Whether the barline crosses the staff of index `group`.
+    */
+    pub fn crosses(&self, group: usize) -> bool {
+        (self.first_staff..=self.last_staff).contains(&group)
+    }
+
+}
+
+// A stroke may stick out of the outer lines by this ratio of the staff spacing, like a stem may not.
+const MAX_OVERHANG: f32 = 0.5;
+// A stroke wider than this ratio of the spacing, or than twice the line thickness, is thick.
+const THIN_RATIO: f32 = 0.25;
+// A stroke wider than the spacing is a blot rather than a barline.
+const MAX_STROKE_RATIO: f32 = 1.0;
+// Strokes of one barline are at most this ratio of the spacing apart.
+const MAX_STROKE_GAP: f32 = 1.0;
+// Repeat dots lie within this ratio of the spacing of the strokes.
+const DOT_DISTANCE: f32 = 1.0;
+
+/**
+The line rows of a staff around a column.
+*/
+struct StaffRows {
+    group: usize,
+    lines: Vec<BTreeMap<usize, f32>>,
+    positions: Vec<f32>,
+    start: usize,
+    end: usize,
+    spacing: f32,
+    thickness: f32
+}
+
+impl StaffRows {
+
+    fn new(group: usize, staff: &StaffGroup, staves: &[Staff]) -> Option<StaffRows> {
+        let (spacing, thickness) = match staff {
+            StaffGroup::Staff(s) => (s.spacing, s.thickness),
+            StaffGroup::Tablature(t) => (t.spacing, t.thickness),
+            StaffGroup::Percussion(_) => return None
+        };
+        let lines = staff.lines();
+
+        Some(StaffRows {
+            group,
+            lines: lines.iter().map(|l| staves[l.track].centres().into_iter().collect()).collect(),
+            positions: lines.iter().map(|l| l.position).collect(),
+            start: lines.iter().map(|l| l.start).min()?,
+            end: lines.iter().map(|l| l.end).max()?,
+            spacing,
+            thickness
+        })
+    }
+
+    /**
+    Centre of a line at a column, taken at the nearest tracked column, in 0-based rows.
+    Tracked centres are 1-based pixel centres, row r being centred on r + 1.5.
+    */
+    fn centre(&self, line: usize, column: usize) -> f32 {
+        let centres = &self.lines[line];
+        let before = centres.range(..=column).next_back();
+        let after = centres.range(column..).next();
+        let centre = match (before, after) {
+            (Some(b), Some(a)) => match column - b.0 <= a.0 - column {
+                true => *b.1,
+                false => *a.1
+            },
+            (Some((_, x)), None) | (None, Some((_, x))) => *x,
+            (None, None) => self.positions[line]
+        };
+        centre - 1.5
+    }
+
+    fn top(&self, column: usize) -> f32 {
+        self.centre(0, column)
+    }
+
+    fn bottom(&self, column: usize) -> f32 {
+        self.centre(self.lines.len() - 1, column)
+    }
+
+}
+
+fn is_ink(buffer: &[u8], height: usize, column: usize, row: usize) -> bool {
+    buffer[(column - 1) * height + row] == 0
+}
+
+/**
+The vertical run of ink holding `row`, bridging single white pixels.
+*/
+fn run(buffer: &[u8], height: usize, column: usize, row: usize) -> Option<(usize, usize)> {
+    if !is_ink(buffer, height, column, row) {
+        return None;
+    }
+    let (mut top, mut bottom) = (row, row);
+    loop {
+        if top > 0 && is_ink(buffer, height, column, top - 1) {
+            top -= 1;
+        } else if top > 1 && is_ink(buffer, height, column, top - 2) {
+            top -= 2;
+        } else {
+            break;
+        }
+    }
+    loop {
+        if bottom + 1 < height && is_ink(buffer, height, column, bottom + 1) {
+            bottom += 1;
+        } else if bottom + 2 < height && is_ink(buffer, height, column, bottom + 2) {
+            bottom += 2;
+        } else {
+            break;
+        }
+    }
+    Some((top, bottom))
+}
+
+/**
+Whether a staff holds a dot in the two spaces around its middle line, within the columns `from` to `to`.
+*/
+fn has_dots(buffer: &[u8], height: usize, rows: &StaffRows, from: usize, to: usize) -> bool {
+    let width = buffer.len() / height;
+    let n = rows.lines.len();
+    if n < 3 {
+        return false;
+    }
+    let middle = (n - 1) / 2;
+    let max_height = (rows.spacing * 0.9).ceil() as usize;
+    let min_height = (rows.spacing / 4.0).floor().max(1.0) as usize;
+
+    [(middle - 1, middle), (middle, middle + 1)].iter().all(|(a, b)| {
+        (from.max(1)..=to.min(width)).any(|column| {
+            let row = (rows.centre(*a, column) + rows.centre(*b, column)) / 2.0;
+            match row < 0.0 || row as usize >= height {
+                true => false,
+                false => run(buffer, height, column, row as usize)
+                    .is_some_and(|(top, bottom)| (min_height..=max_height).contains(&(bottom - top + 1)))
+            }
+        })
+    })
+}
+
+fn classify(strokes: &[(usize, usize)], thin: f32, before: bool, after: bool) -> BarlineKind {
+    match (before, after) {
+        (true, true) => return BarlineKind::RepeatBoth,
+        (true, false) => return BarlineKind::RepeatEnd,
+        (false, true) => return BarlineKind::RepeatStart,
+        _ => ()
+    }
+    let thick = strokes.iter().any(|(s, e)| (e - s + 1) as f32 > thin);
+    match (thick, strokes.len()) {
+        (true, _) => BarlineKind::Final,
+        (false, 1) => BarlineKind::Single,
+        (false, _) => BarlineKind::Double
+    }
+}
+
+/**
+Finds the barlines of the staves, from left to right for every span of staves, top span first.
+
+buffer : The binarized column-major buffer given to `staves::detect_staves`.
+height : The column length.
+staves : The tracked lines.
+groups : The staves from `systems::group_staves`, percussion lines having no barline.
+
+A column belongs to a barline when a run of ink covers a staff from its top to its bottom line
+without sticking out by more than half a spacing, or spans consecutive staves that way.
+Strokes are made of consecutive such columns, and close strokes make a barline.
+*/
+pub fn detect_barlines(buffer: &[u8], height: usize, staves: &[Staff], groups: &[StaffGroup]) -> Vec<Barline> {
+    let mut rows = groups
+        .iter()
+        .enumerate()
+        .filter_map(|(g, group)| StaffRows::new(g, group, staves))
+        .collect::<Vec<StaffRows>>();
+    rows.sort_by(|a, b| a.positions[0].partial_cmp(&b.positions[0]).unwrap_or(std::cmp::Ordering::Equal));
+
+    // Columns of every span of staves, as indices in rows.
+    let mut spans = BTreeMap::<(usize, usize), BTreeSet<usize>>::new();
+
+    for (first, staff) in rows.iter().enumerate() {
+        // Lines may be tracked short of the barlines closing them.
+        let margin = staff.spacing.ceil() as usize;
+        let columns = staff.start.saturating_sub(margin).max(1)..=(staff.end + margin).min(buffer.len() / height);
+        for column in columns {
+            let (top, bottom) = (staff.top(column), staff.bottom(column));
+            let overhang = MAX_OVERHANG * staff.spacing;
+            let middle = ((top + bottom) / 2.0) as usize;
+            if top < 0.0 || middle >= height {continue;}
+
+            let (run_top, run_bottom) = match run(buffer, height, column, middle) {
+                Some(r) => r,
+                None => continue
+            };
+            if run_top > top as usize || (run_top as f32) < top - staff.thickness / 2.0 - overhang {continue;}
+
+            let last = (first..rows.len())
+                .take_while(|s| *s == first || rows[*s].start <= column + margin && column <= rows[*s].end + margin)
+                .filter(|s| {
+                    let bottom = rows[*s].bottom(column);
+                    run_bottom >= bottom as usize
+                        && run_bottom as f32 + 1.0 <= bottom + rows[*s].thickness / 2.0 + overhang
+                })
+                .last();
+            if let Some(last) = last {
+                spans.entry((first, last)).or_default().insert(column);
+            }
+        }
+    }
+
+    let mut barlines = Vec::new();
+
+    for ((first, last), columns) in spans {
+        let staff = &rows[first];
+        let thin = (THIN_RATIO * staff.spacing).max(2.0 * staff.thickness);
+        let max_gap = (MAX_STROKE_GAP * staff.spacing) as usize;
+        let dots = (DOT_DISTANCE * staff.spacing).ceil() as usize;
+
+        let mut strokes = Vec::<(usize, usize)>::new();
+        for column in columns {
+            match strokes.last_mut() {
+                Some((_, end)) if *end + 1 == column => *end = column,
+                _ => strokes.push((column, column))
+            }
+        }
+        strokes.retain(|(s, e)| {
+            let wide = (e - s + 1) as f32 > MAX_STROKE_RATIO * staff.spacing;
+            if wide {
+                debug!("Reject stroke {:?}-{:?} wider than the spacing", s, e);
+            }
+            !wide
+        });
+
+        let mut groups = Vec::<Vec<(usize, usize)>>::new();
+        for stroke in strokes {
+            match groups.last_mut() {
+                Some(g) if stroke.0 - g[g.len() - 1].1 - 1 <= max_gap => g.push(stroke),
+                _ => groups.push(vec![stroke])
+            }
+        }
+
+        for strokes in groups {
+            let (start, end) = (strokes[0].0, strokes[strokes.len() - 1].1);
+            let side = |from: usize, to: usize| from <= to
+                && (first..=last).all(|s| has_dots(buffer, height, &rows[s], from, to));
+            let before = side(start.saturating_sub(dots).max(1), start - 1);
+            let after = side(end + 1, end + dots);
+            let kind = classify(&strokes, thin, before, after);
+
+            debug!("Barline {:?} at columns {:?}-{:?} over staves {:?}-{:?}", kind, start, end, first, last);
+            barlines.push(Barline {
+                kind,
+                start,
+                end,
+                strokes,
+                first_staff: rows[first].group,
+                last_staff: rows[last].group
+            });
+        }
+    }
+
+    barlines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{GrayImage, Luma};
+
+    use crate::synthetic::{generate, GroundTruth, PageConfig};
+    use crate::{ScanConfig, ScanResult, Scanner};
+
+    /**
+    Two staves of 12 pixels spacing and 2 pixels thick lines, on a 400x240 page.
+    */
+    fn page() -> (GrayImage, GroundTruth) {
+        let page = generate(&PageConfig { width: 400, height: 240, systems: 2, ..PageConfig::default() });
+        (page.image, page.truth)
+    }
+
+    fn row(truth: &GroundTruth, line: usize, column: usize) -> i64 {
+        let (_, centre) = truth.lines[line].points.iter().find(|(c, _)| *c == column).unwrap();
+        (centre - 1.5).round() as i64
+    }
+
+    /**
+    Fills the 1-based columns `from` to `to` between the top of line `top` and the bottom of line `bottom`.
+    */
+    fn stroke(img: &mut GrayImage, truth: &GroundTruth, (from, to): (usize, usize), top: usize, bottom: usize) {
+        for column in from..=to {
+            for r in row(truth, top, column) - 1..=row(truth, bottom, column) + 1 {
+                img.put_pixel(column as u32 - 1, r as u32, Luma([0]));
+            }
+        }
+    }
+
+    /**
+    Draws 4x4 dots in the two spaces around the middle line of a staff, from the 1-based column `from`.
+    */
+    fn dots(img: &mut GrayImage, truth: &GroundTruth, staff: usize, from: usize) {
+        for space in &[1, 2] {
+            let centre = (row(truth, 5 * staff + space, from) + row(truth, 5 * staff + space + 1, from)) / 2;
+            for column in from..from + 4 {
+                for r in centre - 1..centre + 3 {
+                    img.put_pixel(column as u32 - 1, r as u32, Luma([0]));
+                }
+            }
+        }
+    }
+
+    fn scan(img: GrayImage) -> ScanResult {
+        let scanner = Scanner::new(ScanConfig { deskew: false, ..ScanConfig::default() });
+        scanner.scan_image(&image::DynamicImage::ImageLuma8(img)).unwrap()
+    }
+
+    #[test]
+    fn test_line_centres_are_0_based_rows() {
+        let (img, truth) = page();
+        let result = scan(img);
+
+        let rows = StaffRows::new(0, &result.groups[0], &result.staves).unwrap();
+
+        for (line, column) in &[(0, 100), (2, 200), (4, 300)] {
+            let (_, centre) = truth.lines[*line].points.iter().find(|(c, _)| c == column).unwrap();
+            assert!((rows.centre(*line, *column) - (centre - 1.5)).abs() < 0.25);
+        }
+    }
+
+    #[test]
+    fn test_barline_kinds() {
+        let (mut img, truth) = page();
+        stroke(&mut img, &truth, (60, 61), 0, 4);
+        stroke(&mut img, &truth, (120, 121), 0, 4);
+        stroke(&mut img, &truth, (126, 127), 0, 4);
+        stroke(&mut img, &truth, (180, 185), 0, 4);
+        stroke(&mut img, &truth, (190, 191), 0, 4);
+        dots(&mut img, &truth, 0, 195);
+        dots(&mut img, &truth, 0, 245);
+        stroke(&mut img, &truth, (252, 253), 0, 4);
+        stroke(&mut img, &truth, (258, 263), 0, 4);
+        stroke(&mut img, &truth, (350, 351), 0, 4);
+        stroke(&mut img, &truth, (356, 361), 0, 4);
+
+        let result = scan(img);
+
+        let found = result.barlines.iter().map(|b| (b.kind, b.start, b.end)).collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            (BarlineKind::Single, 60, 61),
+            (BarlineKind::Double, 120, 127),
+            (BarlineKind::RepeatStart, 180, 191),
+            (BarlineKind::RepeatEnd, 252, 263),
+            (BarlineKind::Final, 350, 361)
+        ]);
+        assert_eq!(result.barlines[1].strokes, vec![(120, 121), (126, 127)]);
+        assert!(result.barlines.iter().all(|b| b.crosses(0) && !b.crosses(1)));
+    }
+
+    #[test]
+    fn test_stems_are_no_barlines() {
+        let (mut img, truth) = page();
+        // A stem from the middle line up, and one crossing the whole staff down to two spaces below it.
+        stroke(&mut img, &truth, (100, 100), 0, 2);
+        for r in row(&truth, 0, 200)..row(&truth, 4, 200) + 24 {
+            img.put_pixel(199, r as u32, Luma([0]));
+        }
+
+        assert!(scan(img).barlines.is_empty());
+    }
+
+    #[test]
+    fn test_barline_across_staves() {
+        let (mut img, truth) = page();
+        stroke(&mut img, &truth, (100, 101), 0, 9);
+        stroke(&mut img, &truth, (200, 201), 5, 9);
+
+        let result = scan(img);
+
+        let found = result.barlines.iter().map(|b| (b.start, b.first_staff, b.last_staff)).collect::<Vec<_>>();
+        assert_eq!(found, vec![(100, 0, 1), (200, 1, 1)]);
+        assert!(result.barlines.iter().all(|b| b.kind == BarlineKind::Single));
+    }
+
+    #[test]
+    fn test_score_sample_grand_staff_barlines() {
+        let result = Scanner::default().scan_path("score_sample/score_sample1.png").unwrap();
+
+        assert_eq!(result.barlines.len(), 3);
+        for barline in &result.barlines {
+            assert_eq!((barline.kind, barline.first_staff, barline.last_staff), (BarlineKind::Single, 0, 1));
+        }
+        assert_eq!(result.barlines.iter().map(|b| b.start).collect::<Vec<usize>>(), vec![21, 201, 331]);
+    }
+}
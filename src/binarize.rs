@@ -0,0 +1,220 @@
+use log::debug;
+
+/**
+Thresholding method used to turn a grayscale buffer into ink (0) and background (255).
+
+Otsu : One global threshold maximizing the between-class variance.
+Sauvola : Local threshold m * (1 + k * (s / 128 - 1)) over a square window.
+Niblack : Local threshold m + k * s over a square window.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Method {
+    #[default]
+    Otsu,
+    Sauvola { window: usize, k: f32 },
+    Niblack { window: usize, k: f32 }
+}
+
+impl Method {
+
+    pub fn sauvola() -> Method {
+        Method::Sauvola { window: 15, k: 0.34 }
+    }
+
+    pub fn niblack() -> Method {
+        Method::Niblack { window: 15, k: -0.2 }
+    }
+
+}
+
+const SAUVOLA_R: f32 = 128.0;
+
+/**
+buffer : Grayscale pixels stored as consecutive chunks of `height` values.
+height : The chunk length, as given to `staves::detect_staves`.
+method : The thresholding method.
+
+Windows are square, so the buffer can be row-major or column-major.
+*/
+pub fn binarize(buffer: &[u8], height: usize, method: &Method) -> Vec<u8> {
+    match method {
+        Method::Otsu => {
+            let threshold = otsu_threshold(buffer);
+            debug!("Otsu threshold:{:?}", threshold);
+            buffer
+                .iter()
+                .map(|v| if *v <= threshold {0} else {255})
+                .collect()
+        },
+        Method::Sauvola { window, k } =>
+            local_threshold(buffer, height, *window, |mean, std_dev|
+                mean * (1.0 + k * (std_dev / SAUVOLA_R - 1.0))
+            ),
+        Method::Niblack { window, k } =>
+            local_threshold(buffer, height, *window, |mean, std_dev|
+                mean + k * std_dev
+            )
+    }
+}
+
+/**
+Returns the highest value still considered as ink.
+A uniform buffer has no split, it is cut in the middle of the range.
+*/
+pub fn otsu_threshold(buffer: &[u8]) -> u8 {
+    let mut histogram = [0usize; 256];
+    for v in buffer {
+        histogram[*v as usize] += 1;
+    }
+
+    let total = buffer.len() as f64;
+    let sum = histogram
+        .iter()
+        .enumerate()
+        .map(|(v, count)| v as f64 * *count as f64)
+        .sum::<f64>();
+
+    let mut weight_background = 0.0;
+    let mut sum_background = 0.0;
+    let mut best: Option<(u8, f64)> = None;
+
+    for (v, count) in histogram.iter().enumerate() {
+        weight_background += *count as f64;
+        if weight_background == 0.0 {continue;}
+
+        let weight_foreground = total - weight_background;
+        if weight_foreground == 0.0 {break;}
+
+        sum_background += v as f64 * *count as f64;
+        let mean_background = sum_background / weight_background;
+        let mean_foreground = (sum - sum_background) / weight_foreground;
+
+        let variance =
+            weight_background * weight_foreground
+            * (mean_background - mean_foreground).powi(2);
+
+        if best.is_none_or(|(_, max)| variance > max) {
+            best = Some((v as u8, variance));
+        }
+    }
+
+    best.map_or(127, |(threshold, _)| threshold)
+}
+
+fn local_threshold<F>(buffer: &[u8], height: usize, window: usize, threshold: F) -> Vec<u8>
+where F: Fn(f32, f32) -> f32 {
+    if height == 0 {return Vec::new();}
+
+    let width = buffer.len() / height;
+    let (sums, squares) = integral_images(buffer, width, height);
+    let half = window / 2;
+    let stride = height + 1;
+
+    let mut res = vec![255; buffer.len()];
+
+    for c in 0..width {
+        let c0 = c.saturating_sub(half);
+        let c1 = (c + half + 1).min(width);
+
+        for r in 0..height {
+            let r0 = r.saturating_sub(half);
+            let r1 = (r + half + 1).min(height);
+
+            let area = ((c1 - c0) * (r1 - r0)) as f64;
+            let rect = |table: &Vec<f64>|
+                table[c1 * stride + r1] - table[c0 * stride + r1]
+                - table[c1 * stride + r0] + table[c0 * stride + r0];
+
+            let mean = rect(&sums) / area;
+            let variance = (rect(&squares) / area - mean * mean).max(0.0);
+
+            if (buffer[c * height + r] as f32) < threshold(mean as f32, variance.sqrt() as f32) {
+                res[c * height + r] = 0;
+            }
+        }
+    }
+
+    res
+}
+
+fn integral_images(buffer: &[u8], width: usize, height: usize) -> (Vec<f64>, Vec<f64>) {
+    let stride = height + 1;
+    let mut sums = vec![0.0; (width + 1) * stride];
+    let mut squares = vec![0.0; (width + 1) * stride];
+
+    for c in 0..width {
+        let mut column_sum = 0.0;
+        let mut column_square = 0.0;
+        for r in 0..height {
+            let v = buffer[c * height + r] as f64;
+            column_sum += v;
+            column_square += v * v;
+            sums[(c + 1) * stride + r + 1] = sums[c * stride + r + 1] + column_sum;
+            squares[(c + 1) * stride + r + 1] = squares[c * stride + r + 1] + column_square;
+        }
+    }
+
+    (sums, squares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_otsu_threshold_split_two_modes() {
+        let buffer = vec![10, 12, 11, 200, 210, 205, 198];
+        let threshold = otsu_threshold(&buffer);
+        assert!((12..198).contains(&threshold));
+    }
+
+    #[test]
+    fn test_otsu_threshold_keep_pure_black_and_white() {
+        assert_eq!(otsu_threshold(&[0, 255, 255, 0]), 0);
+        assert_eq!(binarize(&[0, 255, 255, 0], 2, &Method::Otsu), vec![0, 255, 255, 0]);
+    }
+
+    #[test]
+    fn test_otsu_uniform_buffer() {
+        assert_eq!(binarize(&[0; 4], 2, &Method::Otsu), vec![0; 4]);
+        assert_eq!(binarize(&[255; 4], 2, &Method::Otsu), vec![255; 4]);
+    }
+
+    #[test]
+    fn test_sauvola_handle_uneven_background() {
+        // Background fades from 250 to 120 while a grey line stays darker than its surroundings.
+        let height = 20;
+        let width = 40;
+        let mut buffer = vec![0; width * height];
+        for c in 0..width {
+            let background = 250 - (c * 130 / width) as u8;
+            for r in 0..height {
+                buffer[c * height + r] = if r == 10 {background / 3} else {background};
+            }
+        }
+
+        let res = binarize(&buffer, height, &Method::sauvola());
+
+        for c in 0..width {
+            assert_eq!(res[c * height + 10], 0);
+            assert_eq!(res[c * height + 2], 255);
+        }
+    }
+
+    #[test]
+    fn test_niblack_detect_dark_pixels() {
+        let height = 9;
+        let mut buffer = vec![180; 9 * height];
+        buffer[4 * height + 4] = 30;
+
+        let res = binarize(&buffer, height, &Method::niblack());
+
+        assert_eq!(res[4 * height + 4], 0);
+        assert_eq!(res.iter().filter(|v| **v == 0).count(), 1);
+    }
+
+    #[test]
+    fn test_binarize_empty_buffer() {
+        assert_eq!(binarize(&[], 0, &Method::sauvola()), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use image::GrayImage;
+
+use crate::error::{Error, Result};
+
+const WHITE_CODES: &[(u16, &str)] = &[
+    (0, "00110101"), (1, "000111"), (2, "0111"), (3, "1000"), (4, "1011"), (5, "1100"),
+    (6, "1110"), (7, "1111"), (8, "10011"), (9, "10100"), (10, "00111"), (11, "01000"),
+    (12, "001000"), (13, "000011"), (14, "110100"), (15, "110101"), (16, "101010"), (17, "101011"),
+    (18, "0100111"), (19, "0001100"), (20, "0001000"), (21, "0010111"), (22, "0000011"), (23, "0000100"),
+    (24, "0101000"), (25, "0101011"), (26, "0010011"), (27, "0100100"), (28, "0011000"), (29, "00000010"),
+    (30, "00000011"), (31, "00011010"), (32, "00011011"), (33, "00010010"), (34, "00010011"), (35, "00010100"),
+    (36, "00010101"), (37, "00010110"), (38, "00010111"), (39, "00101000"), (40, "00101001"), (41, "00101010"),
+    (42, "00101011"), (43, "00101100"), (44, "00101101"), (45, "00000100"), (46, "00000101"), (47, "00001010"),
+    (48, "00001011"), (49, "01010010"), (50, "01010011"), (51, "01010100"), (52, "01010101"), (53, "00100100"),
+    (54, "00100101"), (55, "01011000"), (56, "01011001"), (57, "01011010"), (58, "01011011"), (59, "01001010"),
+    (60, "01001011"), (61, "00110010"), (62, "00110011"), (63, "00110100"),
+    (64, "11011"), (128, "10010"), (192, "010111"), (256, "0110111"), (320, "00110110"), (384, "00110111"),
+    (448, "01100100"), (512, "01100101"), (576, "01101000"), (640, "01100111"), (704, "011001100"), (768, "011001101"),
+    (832, "011010010"), (896, "011010011"), (960, "011010100"), (1024, "011010101"), (1088, "011010110"),
+    (1152, "011010111"), (1216, "011011000"), (1280, "011011001"), (1344, "011011010"), (1408, "011011011"),
+    (1472, "010011000"), (1536, "010011001"), (1600, "010011010"), (1664, "011000"), (1728, "010011011")
+];
+
+const BLACK_CODES: &[(u16, &str)] = &[
+    (0, "0000110111"), (1, "010"), (2, "11"), (3, "10"), (4, "011"), (5, "0011"),
+    (6, "0010"), (7, "00011"), (8, "000101"), (9, "000100"), (10, "0000100"), (11, "0000101"),
+    (12, "0000111"), (13, "00000100"), (14, "00000111"), (15, "000011000"), (16, "0000010111"), (17, "0000011000"),
+    (18, "0000001000"), (19, "00001100111"), (20, "00001101000"), (21, "00001101100"), (22, "00000110111"),
+    (23, "00000101000"), (24, "00000010111"), (25, "00000011000"), (26, "000011001010"), (27, "000011001011"),
+    (28, "000011001100"), (29, "000011001101"), (30, "000001101000"), (31, "000001101001"), (32, "000001101010"),
+    (33, "000001101011"), (34, "000011010010"), (35, "000011010011"), (36, "000011010100"), (37, "000011010101"),
+    (38, "000011010110"), (39, "000011010111"), (40, "000001101100"), (41, "000001101101"), (42, "000011011010"),
+    (43, "000011011011"), (44, "000001010100"), (45, "000001010101"), (46, "000001010110"), (47, "000001010111"),
+    (48, "000001100100"), (49, "000001100101"), (50, "000001010010"), (51, "000001010011"), (52, "000000100100"),
+    (53, "000000110111"), (54, "000000111000"), (55, "000000100111"), (56, "000000101000"), (57, "000001011000"),
+    (58, "000001011001"), (59, "000000101011"), (60, "000000101100"), (61, "000001011010"), (62, "000001100110"),
+    (63, "000001100111"),
+    (64, "0000001111"), (128, "000011001000"), (192, "000011001001"), (256, "000001011011"), (320, "000000110011"),
+    (384, "000000110100"), (448, "000000110101"), (512, "0000001101100"), (576, "0000001101101"),
+    (640, "0000001001010"), (704, "0000001001011"), (768, "0000001001100"), (832, "0000001001101"),
+    (896, "0000001110010"), (960, "0000001110011"), (1024, "0000001110100"), (1088, "0000001110101"),
+    (1152, "0000001110110"), (1216, "0000001110111"), (1280, "0000001010010"), (1344, "0000001010011"),
+    (1408, "0000001010100"), (1472, "0000001010101"), (1536, "0000001011010"), (1600, "0000001011011"),
+    (1664, "0000001100100"), (1728, "0000001100101")
+];
+
+// Make-up codes shared by both colours for runs longer than 1728 pixels.
+const EXTENDED_CODES: &[(u16, &str)] = &[
+    (1792, "00000001000"), (1856, "00000001100"), (1920, "00000001101"), (1984, "000000010010"),
+    (2048, "000000010011"), (2112, "000000010100"), (2176, "000000010101"), (2240, "000000010110"),
+    (2304, "000000010111"), (2368, "000000011100"), (2432, "000000011101"), (2496, "000000011110"),
+    (2560, "000000011111")
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Pass,
+    Horizontal,
+    Vertical(isize),
+    EndOfBlock
+}
+
+const MODE_CODES: &[(Mode, &str)] = &[
+    (Mode::Vertical(0), "1"), (Mode::Vertical(1), "011"), (Mode::Vertical(-1), "010"),
+    (Mode::Horizontal, "001"), (Mode::Pass, "0001"),
+    (Mode::Vertical(2), "000011"), (Mode::Vertical(-2), "000010"),
+    (Mode::Vertical(3), "0000011"), (Mode::Vertical(-3), "0000010"),
+    (Mode::EndOfBlock, "000000000001")
+];
+
+type CodeTable<T> = HashMap<(usize, u16), T>;
+
+fn code_table<T: Copy>(codes: &[(T, &str)]) -> CodeTable<T> {
+    codes
+        .iter()
+        .map(|(value, code)| ((code.len(), u16::from_str_radix(code, 2).unwrap_or(0)), *value))
+        .collect()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> BitReader<'a> {
+
+    fn bit(&mut self) -> Option<u16> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u16)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len() * 8
+    }
+
+    /**
+    Reads the shortest code of the table, None at the end of the data.
+    */
+    fn code<T: Copy>(&mut self, table: &CodeTable<T>) -> Result<Option<T>> {
+        let mut code = 0;
+        for len in 1..=13 {
+            code = match self.bit() {
+                Some(bit) => (code << 1) | bit,
+                None => return Ok(None)
+            };
+            if let Some(value) = table.get(&(len, code)) {
+                return Ok(Some(*value));
+            }
+        }
+        Err(Error::UnsupportedImage(format!("invalid CCITT code at bit {}", self.position)))
+    }
+
+}
+
+struct Decoder<'a> {
+    reader: BitReader<'a>,
+    white: CodeTable<u16>,
+    black: CodeTable<u16>,
+    modes: CodeTable<Mode>
+}
+
+impl<'a> Decoder<'a> {
+
+    fn run(&mut self, white: bool) -> Result<Option<usize>> {
+        let mut total = 0;
+        loop {
+            let table = if white {&self.white} else {&self.black};
+            match self.reader.code(table)? {
+                Some(run) if run < 64 => return Ok(Some(total + run as usize)),
+                Some(run) => total += run as usize,
+                None => return Ok(None)
+            }
+        }
+    }
+
+    /**
+    Changing elements of the next coding line, None at the end of the data.
+    */
+    fn line(&mut self, reference: &[usize], columns: usize) -> Result<Option<Vec<usize>>> {
+        let mut coding = Vec::new();
+        let mut a0 = -1isize;
+        let mut white = true;
+
+        while a0 < columns as isize {
+            // b1 is the first changing element right of a0 and of the opposite colour,
+            // a change to black being at an even index of the reference line.
+            let i = (0..reference.len())
+                .find(|i| (i % 2 == 0) == white && reference[*i] as isize > a0)
+                .unwrap_or(reference.len());
+            let b1 = reference.get(i).copied().unwrap_or(columns) as isize;
+            let b2 = reference.get(i + 1).copied().unwrap_or(columns) as isize;
+
+            let mode = match self.reader.code(&self.modes)? {
+                Some(mode) => mode,
+                None => return Ok(None)
+            };
+
+            match mode {
+                Mode::Pass => a0 = b2,
+                Mode::Horizontal => {
+                    let (r1, r2) = match (self.run(white)?, self.run(!white)?) {
+                        (Some(r1), Some(r2)) => (r1 as isize, r2 as isize),
+                        _ => return Ok(None)
+                    };
+                    let a1 = a0.max(0) + r1;
+                    coding.push(a1.min(columns as isize) as usize);
+                    coding.push((a1 + r2).min(columns as isize) as usize);
+                    a0 = a1 + r2;
+                },
+                Mode::Vertical(d) => {
+                    let a1 = b1 + d;
+                    if a1 < a0 || a1 > columns as isize {
+                        return Err(Error::UnsupportedImage(format!("invalid CCITT vertical code at column {}", a1)));
+                    }
+                    coding.push(a1 as usize);
+                    a0 = a1;
+                    white = !white;
+                },
+                Mode::EndOfBlock => return Ok(None)
+            }
+        }
+
+        Ok(Some(coding))
+    }
+
+}
+
+/**
+Decodes CCITT Group 4 (T.6) data, as found in bilevel PDF scans.
+
+columns : The image width in pixel.
+rows : The image height in pixel, 0 to decode until the end of the data.
+
+Returns the image with black pixels at 0 and white ones at 255, missing rows left white.
+*/
+pub fn decode_group4(data: &[u8], columns: usize, rows: usize) -> Result<GrayImage> {
+    let mut decoder = Decoder {
+        reader: BitReader { data, position: 0 },
+        white: code_table(&[WHITE_CODES, EXTENDED_CODES].concat()),
+        black: code_table(&[BLACK_CODES, EXTENDED_CODES].concat()),
+        modes: code_table(MODE_CODES)
+    };
+
+    let mut lines = Vec::<Vec<usize>>::new();
+    let mut reference = Vec::new();
+
+    while (rows == 0 || lines.len() < rows) && !decoder.reader.is_empty() {
+        match decoder.line(&reference, columns)? {
+            Some(line) => {
+                lines.push(line.clone());
+                reference = line;
+            },
+            None => break
+        }
+    }
+
+    let height = if rows == 0 {lines.len()} else {rows};
+    let mut img = GrayImage::from_pixel(columns as u32, height as u32, image::Luma([255]));
+
+    for (y, line) in lines.iter().enumerate() {
+        for run in line.chunks(2) {
+            let end = run.get(1).copied().unwrap_or(columns);
+            for x in run[0]..end {
+                img.put_pixel(x as u32, y as u32, image::Luma([0]));
+            }
+        }
+    }
+
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits_to_bytes(bits: &str) -> Vec<u8> {
+        bits.as_bytes()
+            .chunks(8)
+            .map(|c| c.iter().enumerate().fold(0, |acc, (i, b)| acc | ((b - b'0') << (7 - i))))
+            .collect()
+    }
+
+    // Codes a run the way an encoder would, make-up codes first.
+    fn run_code(codes: &[(u16, &str)], mut run: usize) -> String {
+        let all = [codes, EXTENDED_CODES].concat();
+        let mut res = String::new();
+        while run >= 64 {
+            let (len, code) = all.iter().filter(|(l, _)| *l >= 64 && *l as usize <= run).max_by_key(|(l, _)| *l).unwrap();
+            res.push_str(code);
+            run -= *len as usize;
+        }
+        res.push_str(all.iter().find(|(l, _)| *l as usize == run).unwrap().1);
+        res
+    }
+
+    #[test]
+    fn test_tables_are_prefix_free() {
+        for codes in &[[WHITE_CODES, EXTENDED_CODES].concat(), [BLACK_CODES, EXTENDED_CODES].concat()] {
+            for (i, (_, a)) in codes.iter().enumerate() {
+                for (_, b) in codes.iter().skip(i + 1) {
+                    assert!(!a.starts_with(b) && !b.starts_with(a), "{} {}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_vertical_modes() {
+        // A blank line, then a black run over columns 4..8 in horizontal mode,
+        // then the same run shifted right by one pixel in vertical mode.
+        let bits = "1".to_string()
+            + "001" + "1011" + "011" + "1"
+            + "011" + "011" + "1"
+            + "000000000001";
+        let img = decode_group4(&bits_to_bytes(&bits), 16, 0).unwrap();
+
+        assert_eq!(img.dimensions(), (16, 3));
+        let black = |y| (0..16).filter(|x| img.get_pixel(*x, y).0[0] == 0).collect::<Vec<u32>>();
+        assert!(black(0).is_empty());
+        assert_eq!(black(1), vec![4, 5, 6, 7]);
+        assert_eq!(black(2), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_decode_pass_mode() {
+        // A black run over columns 2..4, then a black pixel at 6 which passes the first run.
+        let bits = "001".to_string() + "0111" + "11" + "1"
+            + "0001" + "000010" + "010" + "1";
+        let img = decode_group4(&bits_to_bytes(&bits), 8, 2).unwrap();
+
+        let black = |y| (0..8).filter(|x| img.get_pixel(*x, y).0[0] == 0).collect::<Vec<u32>>();
+        assert_eq!(black(0), vec![2, 3]);
+        assert_eq!(black(1), vec![6]);
+    }
+
+    #[test]
+    fn test_decode_horizontal_runs() {
+        let columns = 5000;
+        let runs = [(0, 3), (70, 1800), (2600, 63), (64, 64)];
+        let mut bits = String::new();
+        let mut expected = Vec::new();
+        for (white, black) in runs.iter() {
+            bits += &("001".to_string() + &run_code(WHITE_CODES, *white) + &run_code(BLACK_CODES, *black));
+            let start = expected.last().map_or(0, |(_, end)| *end) + white;
+            expected.push((start, start + black));
+        }
+        bits += "1";
+
+        let img = decode_group4(&bits_to_bytes(&bits), columns, 1).unwrap();
+
+        let mut x = 0;
+        for (start, end) in expected {
+            assert!((x..start).all(|x| img.get_pixel(x as u32, 0).0[0] == 255));
+            assert!((start..end).all(|x| img.get_pixel(x as u32, 0).0[0] == 0));
+            x = end;
+        }
+        assert!((x..columns).all(|x| img.get_pixel(x as u32, 0).0[0] == 255));
+    }
+
+    #[test]
+    fn test_decode_truncated_data_keep_white_rows() {
+        let img = decode_group4(&bits_to_bytes("1"), 8, 4).unwrap();
+        assert_eq!(img.dimensions(), (8, 4));
+        assert!(img.pixels().all(|p| p.0[0] == 255));
+    }
+}
@@ -0,0 +1,334 @@
+use image::{GrayImage, Luma};
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::error::Result;
+
+/**
+A defect of real scans, applied to a grayscale image by `Pipeline::apply`.
+
+Kanungo : Edge noise of a bilevel page, thresholded at 128: an ink pixel at distance d of the background
+flips with probability alpha0 * exp(-alpha * d²) + eta, a background pixel at distance d of the ink
+with beta0 * exp(-beta * d²) + eta, then a closing by a `closing` pixels wide square fills the holes.
+SaltAndPepper : Every pixel turns white with probability `salt` and black with probability `pepper`.
+BleedThrough : The mirrored page, blurred by a gaussian of `blur` pixels, shows through with opacity `strength`.
+Illumination : Light falls by `gradient` across the page in a random direction, and by `vignette` in the corners.
+Jpeg : A JPEG encoding at `quality`, from 1 to 100.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Degradation {
+    Kanungo { alpha0: f32, alpha: f32, beta0: f32, beta: f32, eta: f32, closing: usize },
+    SaltAndPepper { salt: f64, pepper: f64 },
+    BleedThrough { strength: f32, blur: f32 },
+    Illumination { gradient: f32, vignette: f32 },
+    Jpeg { quality: u8 }
+}
+
+impl Degradation {
+
+    pub fn kanungo() -> Degradation {
+        Degradation::Kanungo { alpha0: 1.0, alpha: 1.5, beta0: 1.0, beta: 1.5, eta: 0.0, closing: 2 }
+    }
+
+}
+
+/**
+Degradations applied in order, all drawing from one random generator.
+
+seed : Seed of the random generator, the same pipeline always gives the same image.
+*/
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub steps: Vec<Degradation>,
+    pub seed: u64
+}
+
+impl Pipeline {
+
+    pub fn new(seed: u64) -> Pipeline {
+        Pipeline { steps: Vec::new(), seed }
+    }
+
+    pub fn then(mut self, step: Degradation) -> Pipeline {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn apply(&self, img: &GrayImage) -> Result<GrayImage> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut img = img.clone();
+        for step in &self.steps {
+            debug!("Apply {:?}", step);
+            img = degrade(&img, step, &mut rng)?;
+        }
+        Ok(img)
+    }
+
+}
+
+fn degrade(img: &GrayImage, step: &Degradation, rng: &mut StdRng) -> Result<GrayImage> {
+    Ok(match *step {
+        Degradation::Kanungo { alpha0, alpha, beta0, beta, eta, closing } =>
+            kanungo(img, (alpha0, alpha), (beta0, beta), eta, closing, rng),
+        Degradation::SaltAndPepper { salt, pepper } => {
+            let mut img = img.clone();
+            for pixel in img.pixels_mut() {
+                let r = rng.gen::<f64>();
+                if r < pepper {
+                    pixel.0[0] = 0;
+                } else if r < pepper + salt {
+                    pixel.0[0] = 255;
+                }
+            }
+            img
+        },
+        Degradation::BleedThrough { strength, blur } => {
+            let mut reverse = image::imageops::flip_horizontal(img);
+            if blur > 0.0 {
+                reverse = image::imageops::blur(&reverse, blur);
+            }
+            GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                let ink = 1.0 - reverse.get_pixel(x, y).0[0] as f32 / 255.0;
+                Luma([(img.get_pixel(x, y).0[0] as f32 * (1.0 - strength * ink)).round() as u8])
+            })
+        },
+        Degradation::Illumination { gradient, vignette } => {
+            let angle = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+            let (w, h) = (img.width().max(1) as f32, img.height().max(1) as f32);
+            GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                // Coordinates from -1 to 1 across the page.
+                let u = 2.0 * (x as f32 + 0.5) / w - 1.0;
+                let v = 2.0 * (y as f32 + 0.5) / h - 1.0;
+                let along = ((u * angle.cos() + v * angle.sin()) / std::f32::consts::SQRT_2 + 1.0) / 2.0;
+                let shade = gradient * along + vignette * (u * u + v * v) / 2.0;
+                Luma([(img.get_pixel(x, y).0[0] as f32 * (1.0 - shade).clamp(0.0, 1.0)).round() as u8])
+            })
+        },
+        Degradation::Jpeg { quality } => {
+            let mut data = Vec::new();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, quality.clamp(1, 100))
+                .encode(img, img.width(), img.height(), image::ColorType::L8)?;
+            image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg)?.into_luma8()
+        }
+    })
+}
+
+/**
+Distance to the nearest pixel whose ink is `target`, by a two-pass chamfer with weights 1 and √2.
+Infinite when there is no such pixel.
+*/
+fn distance(ink: &[bool], width: usize, height: usize, target: bool) -> Vec<f32> {
+    let mut d = ink.iter().map(|i| match *i == target {
+        true => 0.0,
+        false => f32::INFINITY
+    }).collect::<Vec<f32>>();
+    let diagonal = std::f32::consts::SQRT_2;
+
+    for y in 0..height {
+        for x in 0..width {
+            let mut v = d[y * width + x];
+            if x > 0 {v = v.min(d[y * width + x - 1] + 1.0);}
+            if y > 0 {
+                v = v.min(d[(y - 1) * width + x] + 1.0);
+                if x > 0 {v = v.min(d[(y - 1) * width + x - 1] + diagonal);}
+                if x + 1 < width {v = v.min(d[(y - 1) * width + x + 1] + diagonal);}
+            }
+            d[y * width + x] = v;
+        }
+    }
+    for y in (0..height).rev() {
+        for x in (0..width).rev() {
+            let mut v = d[y * width + x];
+            if x + 1 < width {v = v.min(d[y * width + x + 1] + 1.0);}
+            if y + 1 < height {
+                v = v.min(d[(y + 1) * width + x] + 1.0);
+                if x > 0 {v = v.min(d[(y + 1) * width + x - 1] + diagonal);}
+                if x + 1 < width {v = v.min(d[(y + 1) * width + x + 1] + diagonal);}
+            }
+            d[y * width + x] = v;
+        }
+    }
+
+    d
+}
+
+/**
+Sets every pixel to `value` when any pixel of the `size` pixels wide square at its top left is `value`.
+*/
+fn spread(ink: &[bool], width: usize, height: usize, size: usize, value: bool) -> Vec<bool> {
+    let mut out = ink.to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let window = (y.saturating_sub(size - 1)..=y)
+                .flat_map(|wy| (x.saturating_sub(size - 1)..=x).map(move |wx| wy * width + wx));
+            if window.into_iter().any(|i| ink[i] == value) {
+                out[y * width + x] = value;
+            }
+        }
+    }
+    out
+}
+
+fn kanungo(img: &GrayImage, (alpha0, alpha): (f32, f32), (beta0, beta): (f32, f32), eta: f32, closing: usize, rng: &mut StdRng) -> GrayImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let ink = img.pixels().map(|p| p.0[0] < 128).collect::<Vec<bool>>();
+    let to_background = distance(&ink, width, height, false);
+    let to_ink = distance(&ink, width, height, true);
+
+    let mut flipped = ink
+        .iter()
+        .enumerate()
+        .map(|(i, is_ink)| {
+            let p = match is_ink {
+                true => alpha0 * (-alpha * to_background[i].powi(2)).exp() + eta,
+                false => beta0 * (-beta * to_ink[i].powi(2)).exp() + eta
+            };
+            *is_ink != rng.gen_bool(p.clamp(0.0, 1.0) as f64)
+        })
+        .collect::<Vec<bool>>();
+
+    if closing > 1 {
+        // The erosion works on the mirrored window, so the closing keeps the ink in place.
+        let dilated = spread(&flipped, width, height, closing, true);
+        let reversed = dilated.iter().rev().copied().collect::<Vec<bool>>();
+        flipped = spread(&reversed, width, height, closing, false).into_iter().rev().collect();
+    }
+
+    GrayImage::from_fn(img.width(), img.height(), |x, y| match flipped[y as usize * width + x as usize] {
+        true => Luma([0]),
+        false => Luma([255])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::evaluation::evaluate_staves;
+    use crate::synthetic::{generate, PageConfig};
+    use crate::{ScanConfig, Scanner};
+
+    fn page() -> GrayImage {
+        generate(&PageConfig { width: 300, height: 200, systems: 2, ..PageConfig::default() }).image
+    }
+
+    fn ink(img: &GrayImage) -> usize {
+        img.pixels().filter(|p| p.0[0] < 128).count()
+    }
+
+    #[test]
+    fn test_pipeline_is_reproducible() {
+        let pipeline = Pipeline::new(5)
+            .then(Degradation::kanungo())
+            .then(Degradation::SaltAndPepper { salt: 0.01, pepper: 0.01 })
+            .then(Degradation::Illumination { gradient: 0.3, vignette: 0.2 });
+
+        let a = pipeline.apply(&page()).unwrap();
+        let b = pipeline.apply(&page()).unwrap();
+        let c = Pipeline { seed: 6, ..pipeline }.apply(&page()).unwrap();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(Pipeline::new(5).apply(&page()).unwrap(), page());
+    }
+
+    #[test]
+    fn test_distance_to_ink() {
+        let ink = vec![false, false, false, false, true];
+        let d = distance(&ink, 5, 1, true);
+        assert_eq!(d, vec![4.0, 3.0, 2.0, 1.0, 0.0]);
+        assert!(distance(&ink, 1, 5, true)[0] == 4.0);
+        assert!(distance(&[false; 4], 2, 2, true)[0].is_infinite());
+    }
+
+    #[test]
+    fn test_kanungo_only_flips_edges() {
+        let img = GrayImage::from_fn(40, 40, |_, y| match (10..30).contains(&y) {
+            true => Luma([0]),
+            false => Luma([255])
+        });
+        let step = Degradation::Kanungo { alpha0: 1.0, alpha: 2.0, beta0: 1.0, beta: 2.0, eta: 0.0, closing: 0 };
+
+        let noisy = Pipeline::new(1).then(step).apply(&img).unwrap();
+
+        assert_ne!(noisy, img);
+        for (x, y, p) in noisy.enumerate_pixels() {
+            if !(6..34).contains(&y) {
+                assert_eq!(p.0[0], img.get_pixel(x, y).0[0], "{} {}", x, y);
+            }
+        }
+        assert!((ink(&noisy) as f32 - ink(&img) as f32).abs() < 0.1 * ink(&img) as f32);
+    }
+
+    #[test]
+    fn test_kanungo_closing_fills_holes() {
+        let mut img = GrayImage::from_pixel(10, 10, Luma([0]));
+        img.put_pixel(4, 4, Luma([255]));
+        let closed = kanungo(&img, (0.0, 1.0), (0.0, 1.0), 0.0, 2, &mut StdRng::seed_from_u64(0));
+        assert_eq!(ink(&closed), 100);
+    }
+
+    #[test]
+    fn test_salt_and_pepper_ratio() {
+        let img = GrayImage::from_pixel(100, 100, Luma([128]));
+
+        let noisy = Pipeline::new(0).then(Degradation::SaltAndPepper { salt: 0.1, pepper: 0.05 }).apply(&img).unwrap();
+
+        let white = noisy.pixels().filter(|p| p.0[0] == 255).count();
+        let black = noisy.pixels().filter(|p| p.0[0] == 0).count();
+        assert!((900..1100).contains(&white), "{}", white);
+        assert!((400..600).contains(&black), "{}", black);
+    }
+
+    #[test]
+    fn test_bleed_through_shows_mirrored_ink() {
+        let mut img = GrayImage::from_pixel(10, 1, Luma([255]));
+        img.put_pixel(0, 0, Luma([0]));
+
+        let bled = Pipeline::new(0).then(Degradation::BleedThrough { strength: 0.2, blur: 0.0 }).apply(&img).unwrap();
+
+        assert_eq!(bled.into_raw(), vec![0, 255, 255, 255, 255, 255, 255, 255, 255, 204]);
+    }
+
+    #[test]
+    fn test_illumination_darkens_corners() {
+        let img = GrayImage::from_pixel(50, 50, Luma([200]));
+
+        let dark = Pipeline::new(0).then(Degradation::Illumination { gradient: 0.0, vignette: 0.5 }).apply(&img).unwrap();
+
+        assert!(dark.get_pixel(0, 0).0[0] < 110);
+        assert!(dark.get_pixel(25, 25).0[0] >= 199);
+    }
+
+    #[test]
+    fn test_jpeg_blocks_stay_close() {
+        let img = page();
+
+        let jpeg = Pipeline::new(0).then(Degradation::Jpeg { quality: 30 }).apply(&img).unwrap();
+
+        assert_eq!(jpeg.dimensions(), img.dimensions());
+        assert_ne!(jpeg, img);
+        let error = jpeg.pixels().zip(img.pixels()).map(|(a, b)| (a.0[0] as f32 - b.0[0] as f32).abs()).sum::<f32>();
+        assert!(error / (300.0 * 200.0) < 10.0);
+    }
+
+    #[test]
+    fn test_detect_staves_on_degraded_page() {
+        let page = generate(&PageConfig { width: 500, height: 300, systems: 2, ..PageConfig::default() });
+        let pipeline = Pipeline::new(3)
+            .then(Degradation::kanungo())
+            .then(Degradation::BleedThrough { strength: 0.3, blur: 1.0 })
+            .then(Degradation::Illumination { gradient: 0.3, vignette: 0.2 })
+            .then(Degradation::SaltAndPepper { salt: 0.001, pepper: 0.001 })
+            .then(Degradation::Jpeg { quality: 50 });
+        let img = pipeline.apply(&page.image).unwrap();
+
+        let scanner = Scanner::new(ScanConfig { deskew: false, ..ScanConfig::default() });
+        let result = scanner.scan_image(&image::DynamicImage::ImageLuma8(img)).unwrap();
+        let evaluation = evaluate_staves(&page.truth, &result.staves, 1.0);
+
+        assert!(evaluation.recall() > 0.9, "{:?}", evaluation);
+        assert!(evaluation.precision() > 0.9, "{:?}", evaluation);
+    }
+}
@@ -0,0 +1,150 @@
+use image::{GrayImage, Luma};
+use log::debug;
+
+// Largest skew in degree looked for.
+pub const MAX_SKEW: f32 = 10.0;
+// Angle step in degree of the first pass, refined tenfold by a second pass.
+const COARSE_STEP: f32 = 0.1;
+// Smaller angles in degree are not worth resampling the page.
+pub const MIN_SKEW: f32 = 0.05;
+
+/**
+Estimates the dominant line angle of a binarized column-major buffer with a projection profile:
+ink rows are sheared by each candidate angle and the sharpest row histogram wins.
+
+Returns degrees, positive when lines go down from left to right.
+*/
+pub fn estimate_skew(buffer_vertical: &[u8], height: usize) -> f32 {
+    if height == 0 {return 0.0;}
+
+    let ink = buffer_vertical
+        .chunks(height)
+        .enumerate()
+        .flat_map(|(c, column)|
+            column
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| **v == 0)
+                .map(move |(r, _)| (c as f32, r as f32))
+        )
+        .collect::<Vec<(f32, f32)>>();
+
+    if ink.is_empty() {return 0.0;}
+
+    let width = buffer_vertical.len() / height;
+    let margin = (width as f32 * MAX_SKEW.to_radians().tan()).ceil() as usize + 1;
+    let mut profile = vec![0u32; height + 2 * margin];
+
+    let mut score = |angle: f32| {
+        let slope = angle.to_radians().tan();
+        profile.iter_mut().for_each(|v| *v = 0);
+        for (c, r) in &ink {
+            let row = (r - c * slope).round() as i64 + margin as i64;
+            if row >= 0 && (row as usize) < profile.len() {
+                profile[row as usize] += 1;
+            }
+        }
+        profile.iter().map(|v| (*v as f64).powi(2)).sum::<f64>()
+    };
+
+    let mut search = |from: f32, to: f32, step: f32| {
+        let steps = ((to - from) / step).round() as i64;
+        (0..=steps)
+            .map(|i| from + i as f32 * step)
+            .map(|angle| (angle, score(angle)))
+            .fold((0.0f32, f64::MIN), |best, (angle, s)|
+                if s > best.1 || (s == best.1 && angle.abs() < best.0.abs()) {(angle, s)} else {best}
+            )
+            .0
+    };
+
+    let coarse = search(-MAX_SKEW, MAX_SKEW, COARSE_STEP);
+    let angle = search(coarse - COARSE_STEP, coarse + COARSE_STEP, COARSE_STEP / 10.0);
+
+    debug!("Estimated skew:{:?} degrees", angle);
+
+    angle
+}
+
+/**
+Rotates a page so that lines with the given skew become horizontal.
+Pixels coming from outside the page are white.
+*/
+pub fn rotate(img: &GrayImage, angle: f32) -> GrayImage {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let cx = (img.width() as f32 - 1.0) / 2.0;
+    let cy = (img.height() as f32 - 1.0) / 2.0;
+
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let dx = x as f32 - cx;
+        let dy = y as f32 - cy;
+        Luma([bilinear(img, cx + cos * dx - sin * dy, cy + sin * dx + cos * dy)])
+    })
+}
+
+fn bilinear(img: &GrayImage, x: f32, y: f32) -> u8 {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+
+    let at = |x: f32, y: f32| {
+        if x < 0.0 || y < 0.0 || x >= img.width() as f32 || y >= img.height() as f32 {
+            255.0
+        } else {
+            img.get_pixel(x as u32, y as u32).0[0] as f32
+        }
+    };
+
+    let v = at(x0, y0) * (1.0 - fx) * (1.0 - fy)
+        + at(x0 + 1.0, y0) * fx * (1.0 - fy)
+        + at(x0, y0 + 1.0) * (1.0 - fx) * fy
+        + at(x0 + 1.0, y0 + 1.0) * fx * fy;
+
+    v.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skewed_lines(width: u32, height: u32, angle: f32) -> GrayImage {
+        let slope = angle.to_radians().tan();
+        GrayImage::from_fn(width, height, |x, y| {
+            let row = y as f32 - x as f32 * slope;
+            let ink = [20.0, 28.0, 36.0, 44.0, 52.0].iter().any(|r| (row - r).abs() < 0.75);
+            Luma([if ink {0} else {255}])
+        })
+    }
+
+    #[test]
+    fn test_estimate_skew() {
+        for angle in &[-3.0, 0.0, 1.5, 4.2] {
+            let img = skewed_lines(200, 100, *angle);
+            let estimate = estimate_skew(&crate::to_column_major(&img), 100);
+            assert!((estimate - angle).abs() < 0.1, "{} estimated as {}", angle, estimate);
+        }
+    }
+
+    #[test]
+    fn test_estimate_skew_of_blank_page() {
+        assert_eq!(estimate_skew(&[255; 20], 4), 0.0);
+        assert_eq!(estimate_skew(&[], 0), 0.0);
+    }
+
+    #[test]
+    fn test_rotate_straighten_lines() {
+        let img = skewed_lines(200, 100, 3.0);
+
+        let straight = rotate(&img, 3.0);
+        let buffer = crate::binarize::binarize(&crate::to_column_major(&straight), 100, &crate::binarize::Method::Otsu);
+
+        assert!(estimate_skew(&buffer, 100).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_rotate_by_zero_keep_image() {
+        let img = skewed_lines(20, 10, 2.0);
+        assert_eq!(rotate(&img, 0.0), img);
+    }
+}
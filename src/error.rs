@@ -0,0 +1,88 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Decode(image::ImageError),
+    Json(serde_json::Error),
+    Tiff(tiff::TiffError),
+    Pdf(lopdf::Error),
+    UnsupportedImage(String),
+    SingularCovariance,
+    EmptyTrack,
+    InvalidDimensions { len: usize, height: usize },
+    UnsupportedSchema { version: u32, supported: u32 }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Decode(e) => write!(f, "Could not decode image: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Tiff(e) => write!(f, "Could not decode TIFF: {}", e),
+            Error::Pdf(e) => write!(f, "Could not read PDF: {}", e),
+            Error::UnsupportedImage(reason) => write!(f, "Unsupported image: {}", reason),
+            Error::SingularCovariance => write!(f, "Could not inverse matrix with determinant equal to zero"),
+            Error::EmptyTrack => write!(f, "A track needs at least one pixel"),
+            Error::InvalidDimensions { len, height } =>
+                write!(f, "A buffer of {} pixels can not be split in columns of {} pixels", len, height),
+            Error::UnsupportedSchema { version, supported } =>
+                write!(f, "Report schema version {} is newer than the supported version {}", version, supported)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Tiff(e) => Some(e),
+            Error::Pdf(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Error {
+        match e {
+            image::ImageError::IoError(e) => Error::Io(e),
+            e => Error::Decode(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<tiff::TiffError> for Error {
+    fn from(e: tiff::TiffError) -> Error {
+        match e {
+            tiff::TiffError::IoError(e) => Error::Io(e),
+            e => Error::Tiff(e)
+        }
+    }
+}
+
+impl From<lopdf::Error> for Error {
+    fn from(e: lopdf::Error) -> Error {
+        match e {
+            lopdf::Error::IO(e) => Error::Io(e),
+            e => Error::Pdf(e)
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::staves::Staff;
+use crate::synthetic::GroundTruth;
+
+/**
+CLEAR MOT counts of tracks against ground truth lines, every column being a frame.
+
+truth_points : Number of columns of every truth line, gaps left out.
+matches : Truth points matched by a track within the distance gate.
+misses : Truth points without matching track.
+false_positives : Track centres matching no truth point.
+id_switches : Truth points matched by another track than the one that matched the line last.
+fragmentations : Times a line is matched again after missed columns.
+total_error : Sum of the vertical distances in pixel of the matches.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Evaluation {
+    pub truth_points: usize,
+    pub matches: usize,
+    pub misses: usize,
+    pub false_positives: usize,
+    pub id_switches: usize,
+    pub fragmentations: usize,
+    pub total_error: f32
+}
+
+/**
+Scores derived from an `Evaluation`, 0 when undefined for lack of truth or tracks.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Scores {
+    pub precision: f32,
+    pub recall: f32,
+    pub mean_error: f32,
+    pub mota: f32
+}
+
+fn ratio(a: usize, b: usize) -> f32 {
+    match b {
+        0 => 0.0,
+        _ => a as f32 / b as f32
+    }
+}
+
+impl Evaluation {
+
+    pub fn precision(&self) -> f32 {
+        ratio(self.matches, self.matches + self.false_positives)
+    }
+
+    pub fn recall(&self) -> f32 {
+        ratio(self.matches, self.truth_points)
+    }
+
+    /**
+    Mean vertical distance in pixel between a matched track centre and the truth.
+    */
+    pub fn mean_error(&self) -> f32 {
+        match self.matches {
+            0 => 0.0,
+            n => self.total_error / n as f32
+        }
+    }
+
+    /**
+    Multiple object tracking accuracy, one minus the misses, false positives and identity switches per truth point.
+    */
+    pub fn mota(&self) -> f32 {
+        match self.truth_points {
+            0 => 0.0,
+            n => 1.0 - (self.misses + self.false_positives + self.id_switches) as f32 / n as f32
+        }
+    }
+
+    pub fn scores(&self) -> Scores {
+        Scores {
+            precision: self.precision(),
+            recall: self.recall(),
+            mean_error: self.mean_error(),
+            mota: self.mota()
+        }
+    }
+
+}
+
+impl std::ops::AddAssign for Evaluation {
+    fn add_assign(&mut self, other: Evaluation) {
+        self.truth_points += other.truth_points;
+        self.matches += other.matches;
+        self.misses += other.misses;
+        self.false_positives += other.false_positives;
+        self.id_switches += other.id_switches;
+        self.fragmentations += other.fragmentations;
+        self.total_error += other.total_error;
+    }
+}
+
+/**
+Evaluates the staves tracked on a page against its ground truth, see `evaluate`.
+*/
+pub fn evaluate_staves(truth: &GroundTruth, staves: &[Staff], max_distance: f32) -> Evaluation {
+    let tracks = staves.iter().map(|staff| staff.centres()).collect::<Vec<Vec<(usize, f32)>>>();
+    evaluate(truth, &tracks, max_distance)
+}
+
+/**
+Matches track centres to truth lines column by column: a line keeps the track it matched last
+while within `max_distance` pixels, the other lines and tracks are paired by the Hungarian algorithm.
+
+tracks : The `(column, centre)` of every track, as given by `Staff::centres`.
+*/
+pub fn evaluate(truth: &GroundTruth, tracks: &[Vec<(usize, f32)>], max_distance: f32) -> Evaluation {
+    let mut truth_columns = BTreeMap::<usize, Vec<(usize, f32)>>::new();
+    for (l, line) in truth.lines.iter().enumerate() {
+        for (column, centre) in &line.points {
+            truth_columns.entry(*column).or_default().push((l, *centre));
+        }
+    }
+    let mut track_columns = BTreeMap::<usize, Vec<(usize, f32)>>::new();
+    for (t, track) in tracks.iter().enumerate() {
+        for (column, centre) in track {
+            track_columns.entry(*column).or_default().push((t, *centre));
+        }
+    }
+
+    let columns = truth_columns.keys().chain(track_columns.keys()).copied().collect::<BTreeSet<usize>>();
+
+    let mut evaluation = Evaluation::default();
+    let mut last = vec![None; truth.lines.len()];
+    let mut interrupted = vec![false; truth.lines.len()];
+    let none = Vec::new();
+
+    for column in columns {
+        let lines = truth_columns.get(&column).unwrap_or(&none);
+        let hypotheses = track_columns.get(&column).unwrap_or(&none);
+
+        let mut matched = vec![None; lines.len()];
+        let mut used = vec![false; hypotheses.len()];
+
+        for (i, (l, centre)) in lines.iter().enumerate() {
+            let kept = hypotheses
+                .iter()
+                .position(|(t, x)| Some(*t) == last[*l] && (x - centre).abs() <= max_distance);
+            if let Some(h) = kept.filter(|h| !used[*h]) {
+                matched[i] = Some(h);
+                used[h] = true;
+            }
+        }
+
+        let rows = (0..lines.len()).filter(|i| matched[*i].is_none()).collect::<Vec<usize>>();
+        let cols = (0..hypotheses.len()).filter(|h| !used[*h]).collect::<Vec<usize>>();
+        let costs = rows
+            .iter()
+            .map(|i| cols
+                .iter()
+                .map(|h| Some((hypotheses[*h].1 - lines[*i].1).abs()).filter(|d| *d <= max_distance))
+                .collect()
+            )
+            .collect::<Vec<Vec<Option<f32>>>>();
+        let cost = max_distance + 1.0;
+        for (r, c) in crate::assignment::assign(&costs, cost, cost).into_iter().enumerate() {
+            if let Some(c) = c {
+                matched[rows[r]] = Some(cols[c]);
+                used[cols[c]] = true;
+            }
+        }
+
+        for (i, (l, centre)) in lines.iter().enumerate() {
+            evaluation.truth_points += 1;
+            match matched[i] {
+                Some(h) => {
+                    let (t, x) = hypotheses[h];
+                    evaluation.matches += 1;
+                    evaluation.total_error += (x - centre).abs();
+                    if last[*l].is_some_and(|previous| previous != t) {
+                        trace!("Line {:?} switches to track {:?} at column {:?}", l, t, column);
+                        evaluation.id_switches += 1;
+                    }
+                    if interrupted[*l] {
+                        evaluation.fragmentations += 1;
+                        interrupted[*l] = false;
+                    }
+                    last[*l] = Some(t);
+                },
+                None => {
+                    evaluation.misses += 1;
+                    interrupted[*l] = last[*l].is_some();
+                }
+            }
+        }
+
+        evaluation.false_positives += used.iter().filter(|u| !**u).count();
+    }
+
+    evaluation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::synthetic::{generate, PageConfig, TruthLine};
+    use crate::{ScanConfig, Scanner};
+
+    fn truth(lines: Vec<Vec<(usize, f32)>>) -> GroundTruth {
+        GroundTruth {
+            width: 20,
+            height: 20,
+            lines: lines
+                .into_iter()
+                .enumerate()
+                .map(|(line, points)| TruthLine { system: 0, line, thickness: 1.0, points })
+                .collect()
+        }
+    }
+
+    fn flat(columns: std::ops::RangeInclusive<usize>, centre: f32) -> Vec<(usize, f32)> {
+        columns.map(|c| (c, centre)).collect()
+    }
+
+    #[test]
+    fn test_perfect_tracks() {
+        let truth = truth(vec![flat(1..=10, 4.5), flat(1..=10, 9.5)]);
+        let tracks = vec![flat(1..=10, 9.75), flat(1..=10, 4.5)];
+
+        let evaluation = evaluate(&truth, &tracks, 1.0);
+
+        assert_eq!((evaluation.truth_points, evaluation.matches, evaluation.misses), (20, 20, 0));
+        assert_eq!((evaluation.false_positives, evaluation.id_switches, evaluation.fragmentations), (0, 0, 0));
+        assert_eq!(evaluation.scores(), Scores { precision: 1.0, recall: 1.0, mean_error: 0.125, mota: 1.0 });
+    }
+
+    #[test]
+    fn test_count_identity_switch() {
+        let truth = truth(vec![flat(1..=10, 4.5)]);
+        let tracks = vec![flat(1..=5, 4.5), flat(6..=10, 4.5)];
+
+        let evaluation = evaluate(&truth, &tracks, 1.0);
+
+        assert_eq!((evaluation.matches, evaluation.id_switches, evaluation.fragmentations), (10, 1, 0));
+        assert!((evaluation.mota() - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_count_fragmentation_and_misses() {
+        let truth = truth(vec![flat(1..=10, 4.5)]);
+        let mut track = flat(1..=3, 4.5);
+        track.extend(flat(7..=10, 4.5));
+
+        let evaluation = evaluate(&truth, &[track], 1.0);
+
+        assert_eq!((evaluation.misses, evaluation.fragmentations, evaluation.id_switches), (3, 1, 0));
+        assert!((evaluation.recall() - 0.7).abs() < 1e-6);
+        assert_eq!(evaluation.precision(), 1.0);
+    }
+
+    #[test]
+    fn test_truth_gap_is_no_fragmentation() {
+        let mut points = flat(1..=3, 4.5);
+        points.extend(flat(7..=10, 4.5));
+        let truth = truth(vec![points.clone()]);
+
+        let evaluation = evaluate(&truth, &[points], 1.0);
+
+        assert_eq!((evaluation.truth_points, evaluation.matches, evaluation.fragmentations), (7, 7, 0));
+    }
+
+    #[test]
+    fn test_distant_track_is_false_positive() {
+        let truth = truth(vec![flat(1..=10, 4.5)]);
+        let tracks = vec![flat(1..=10, 7.0), flat(11..=12, 4.5)];
+
+        let evaluation = evaluate(&truth, &tracks, 2.0);
+
+        assert_eq!((evaluation.matches, evaluation.misses, evaluation.false_positives), (0, 10, 12));
+        assert_eq!((evaluation.precision(), evaluation.recall()), (0.0, 0.0));
+        assert!((evaluation.mota() + 1.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_keep_previous_match_within_gate() {
+        // The second track comes closer than the first one, which still matches.
+        let truth = truth(vec![flat(1..=4, 4.5)]);
+        let tracks = vec![flat(1..=4, 5.0), flat(3..=4, 4.5)];
+
+        let evaluation = evaluate(&truth, &tracks, 1.0);
+
+        assert_eq!((evaluation.id_switches, evaluation.false_positives), (0, 2));
+    }
+
+    #[test]
+    fn test_add_evaluations() {
+        let truth = truth(vec![flat(1..=10, 4.5)]);
+        let mut total = evaluate(&truth, &[flat(1..=10, 4.5)], 1.0);
+        total += evaluate(&truth, &[], 1.0);
+
+        assert_eq!((total.truth_points, total.matches, total.misses), (20, 10, 10));
+    }
+
+    #[test]
+    fn test_evaluate_generated_page() {
+        let page = generate(&PageConfig { width: 500, height: 300, systems: 2, gaps: 1, ..PageConfig::default() });
+        let scanner = Scanner::new(ScanConfig { deskew: false, ..ScanConfig::default() });
+        let result = scanner.scan_image(&image::DynamicImage::ImageLuma8(page.image)).unwrap();
+
+        let evaluation = evaluate_staves(&page.truth, &result.staves, 1.0);
+
+        assert!(evaluation.recall() > 0.95, "{:?}", evaluation);
+        assert!(evaluation.precision() > 0.95, "{:?}", evaluation);
+        assert!(evaluation.mean_error() < 0.5, "{:?}", evaluation);
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use image::{GenericImage, GrayImage, Luma};
+use log::warn;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+use crate::error::{Error, Result};
+
+/**
+Opens an image file and converts it to 8 bits grayscale.
+*/
+pub fn open_luma<P: AsRef<Path>>(path: P) -> Result<GrayImage> {
+    Ok(image::open(path)?.into_luma8())
+}
+
+/**
+Iterator over the 1-based index and the grayscale image of every page of a document, see `open_pages`.
+*/
+pub struct Pages {
+    source: Source,
+    index: usize
+}
+
+enum Source {
+    Single(Option<GrayImage>),
+    Tiff(Box<Decoder<BufReader<File>>>, bool),
+    Pdf(Box<lopdf::Document>, std::vec::IntoIter<lopdf::ObjectId>)
+}
+
+/**
+Opens a document of one or more pages, recognized by its first bytes:
+every directory of a multi-page TIFF, the raster image of every page of a PDF,
+or any single image `open_luma` reads.
+*/
+pub fn open_pages<P: AsRef<Path>>(path: P) -> Result<Pages> {
+    let mut magic = [0; 4];
+    let len = File::open(&path)?.read(&mut magic)?;
+
+    let source = match &magic[..len] {
+        b"%PDF" => {
+            let doc = lopdf::Document::load(&path)?;
+            let pages = doc.get_pages().into_values().collect::<Vec<_>>();
+            Source::Pdf(Box::new(doc), pages.into_iter())
+        },
+        b"II*\0" | b"MM\0*" => Source::Tiff(Box::new(Decoder::new(BufReader::new(File::open(&path)?))?), true),
+        _ => Source::Single(Some(open_luma(&path)?))
+    };
+
+    Ok(Pages { source, index: 0 })
+}
+
+fn tiff_luma(decoder: &mut Decoder<BufReader<File>>) -> Result<GrayImage> {
+    let (width, height) = decoder.dimensions()?;
+    let colortype = decoder.colortype()?;
+
+    if colortype == ColorType::Gray(1) {
+        return tiff_bilevel(decoder, width, height);
+    }
+
+    let samples = match decoder.read_image()? {
+        DecodingResult::U8(samples) => samples,
+        DecodingResult::U16(samples) => samples.iter().map(|v| (v >> 8) as u8).collect(),
+        _ => return Err(Error::UnsupportedImage(format!("TIFF samples of {:?}", colortype)))
+    };
+
+    let luma = match colortype {
+        ColorType::Gray(8) | ColorType::Gray(16) => samples,
+        ColorType::GrayA(8) | ColorType::GrayA(16) => samples.chunks(2).map(|p| p[0]).collect(),
+        ColorType::RGB(8) | ColorType::RGB(16) => image::RgbImage::from_raw(width, height, samples)
+            .map(|img| image::DynamicImage::ImageRgb8(img).into_luma8().into_raw())
+            .unwrap_or_default(),
+        ColorType::RGBA(8) | ColorType::RGBA(16) => image::RgbaImage::from_raw(width, height, samples)
+            .map(|img| image::DynamicImage::ImageRgba8(img).into_luma8().into_raw())
+            .unwrap_or_default(),
+        c => return Err(Error::UnsupportedImage(format!("TIFF pages of {:?}", c)))
+    };
+
+    GrayImage::from_raw(width, height, luma)
+        .ok_or_else(|| Error::UnsupportedImage(format!("truncated TIFF page of {}x{}", width, height)))
+}
+
+/**
+Decodes a 1 bit TIFF page, uncompressed or CCITT Group 4 as most archive scans are,
+from its raw strips.
+*/
+fn tiff_bilevel(decoder: &mut Decoder<BufReader<File>>, width: u32, height: u32) -> Result<GrayImage> {
+    let compression = decoder.find_tag_unsigned::<u16>(Tag::Compression)?.unwrap_or(1);
+    let white_is_zero = decoder.find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)? == Some(0);
+    let lsb_first = decoder.find_tag_unsigned::<u16>(Tag::FillOrder)? == Some(2);
+    let rows_per_strip = decoder.find_tag_unsigned::<u32>(Tag::RowsPerStrip)?.unwrap_or(height).clamp(1, height.max(1));
+    let offsets = decoder.get_tag_u64_vec(Tag::StripOffsets)?;
+    let counts = decoder.get_tag_u64_vec(Tag::StripByteCounts)?;
+
+    let mut img = GrayImage::from_pixel(width, height, Luma([255]));
+    let row_len = (width as usize).div_ceil(8);
+
+    for (strip, (offset, count)) in offsets.iter().zip(&counts).enumerate() {
+        let top = strip as u32 * rows_per_strip;
+        if top >= height {break;}
+        let rows = rows_per_strip.min(height - top);
+
+        decoder.goto_offset_u64(*offset)?;
+        let mut data = (0..*count).map(|_| decoder.read_byte()).collect::<std::io::Result<Vec<u8>>>()?;
+        if lsb_first {
+            data.iter_mut().for_each(|b| *b = b.reverse_bits());
+        }
+
+        // The 1 sample is black on a white-is-zero page, for both encodings.
+        let strip_img = match compression {
+            1 => GrayImage::from_fn(width, rows, |x, y| {
+                match data.get(y as usize * row_len + x as usize / 8) {
+                    Some(byte) if (byte >> (7 - x % 8) & 1 == 1) == white_is_zero => Luma([0]),
+                    _ => Luma([255])
+                }
+            }),
+            4 => {
+                let mut strip_img = crate::ccitt::decode_group4(&data, width as usize, rows as usize)?;
+                if !white_is_zero {
+                    image::imageops::invert(&mut strip_img);
+                }
+                strip_img
+            },
+            c => return Err(Error::UnsupportedImage(format!("1 bit TIFF pages of compression {}", c)))
+        };
+        img.copy_from(&strip_img, 0, top)?;
+    }
+
+    Ok(img)
+}
+
+impl Pages {
+
+    /**
+    The 1-based index of the page last returned, failed or not, 0 before the first one.
+    */
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+}
+
+impl Iterator for Pages {
+    type Item = Result<(usize, GrayImage)>;
+
+    fn next(&mut self) -> Option<Result<(usize, GrayImage)>> {
+        self.index += 1;
+        let image = match &mut self.source {
+            Source::Single(img) => img.take().map(Ok),
+            Source::Tiff(decoder, first) => {
+                if !*first && !decoder.more_images() {return None;}
+                let next = match *first {
+                    true => Ok(()),
+                    false => decoder.next_image().map_err(Error::from)
+                };
+                *first = false;
+                match next {
+                    Ok(()) => Some(tiff_luma(decoder)),
+                    // The pages after an unreadable directory can not be found.
+                    Err(e) => {
+                        self.source = Source::Single(None);
+                        Some(Err(e))
+                    }
+                }
+            },
+            Source::Pdf(doc, pages) => loop {
+                let id = pages.next()?;
+                match crate::pdf::page_image(doc, id) {
+                    Ok(None) => {
+                        warn!("Skip page {} without raster image", self.index);
+                        self.index += 1;
+                    },
+                    res => break res.transpose()
+                }
+            }
+        };
+        image.map(|res| res.map(|img| (self.index, img)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustscanscore_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        assert!(matches!(open_luma("score_sample/missing.png"), Err(Error::Io(_))));
+        assert!(matches!(open_pages("score_sample/missing.png"), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_open_invalid_file() {
+        assert!(matches!(open_luma("Cargo.toml"), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn test_open_single_image_pages() {
+        let pages = open_pages("score_sample/score_sample1.png").unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].0, 1);
+        assert_eq!(pages[0].1.dimensions(), (338, 149));
+    }
+
+    #[test]
+    fn test_open_multi_page_tiff() {
+        let path = temp_path("pages.tif");
+        {
+            let mut tiff = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+            tiff.write_image::<colortype::Gray8>(4, 2, &[0; 8]).unwrap();
+            tiff.write_image::<colortype::RGB8>(3, 1, &[255, 255, 255, 0, 0, 0, 255, 0, 0]).unwrap();
+        }
+
+        let pages = open_pages(&path).unwrap().collect::<Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.iter().map(|(i, _)| *i).collect::<Vec<usize>>(), vec![1, 2]);
+        assert_eq!(pages[0].1.dimensions(), (4, 2));
+        assert_eq!(pages[1].1.clone().into_raw(), vec![255, 0, 54]);
+    }
+
+    /**
+    A little-endian TIFF of 1 bit pages, each given as width, height, compression, photometric interpretation and strip.
+    */
+    fn bilevel_tiff(pages: &[(u32, u32, u16, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+
+        for (i, (width, height, compression, photometric, data)) in pages.iter().enumerate() {
+            let ifd_len = 2 + 8 * 12 + 4;
+            let data_offset = tiff.len() as u32 + ifd_len;
+            let entries = [
+                (256, *width), (257, *height), (258, 1), (259, *compression as u32), (262, *photometric as u32),
+                (273, data_offset), (278, *height), (279, data.len() as u32)
+            ];
+            tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for (tag, value) in &entries {
+                tiff.extend_from_slice(&(*tag as u16).to_le_bytes());
+                tiff.extend_from_slice(&4u16.to_le_bytes());
+                tiff.extend_from_slice(&1u32.to_le_bytes());
+                tiff.extend_from_slice(&value.to_le_bytes());
+            }
+            let next = match i + 1 < pages.len() {
+                true => data_offset + data.len() as u32,
+                false => 0
+            };
+            tiff.extend_from_slice(&next.to_le_bytes());
+            tiff.extend_from_slice(data);
+        }
+        tiff
+    }
+
+    #[test]
+    fn test_open_bilevel_tiff() {
+        let path = temp_path("bilevel.tif");
+        std::fs::write(&path, bilevel_tiff(&[
+            // White is zero, then black is zero, rows padded to a whole byte.
+            (10, 2, 1, 0, vec![0b1000_0000, 0b0100_0000, 0xFF, 0xC0]),
+            (3, 1, 1, 1, vec![0b0100_0000]),
+            // A blank row, then black columns 4 to 7 and 5 to 8, in Group 4.
+            (16, 3, 4, 0, vec![0x9B, 0x76, 0xE0, 0x02])
+        ])).unwrap();
+
+        let pages = open_pages(&path).unwrap().collect::<Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.len(), 3);
+        let black = |img: &GrayImage, y| (0..img.width()).filter(|x| img.get_pixel(*x, y).0[0] == 0).collect::<Vec<u32>>();
+        assert_eq!(black(&pages[0].1, 0), vec![0, 9]);
+        assert_eq!(black(&pages[0].1, 1), (0..10).collect::<Vec<u32>>());
+        assert_eq!(pages[1].1.clone().into_raw(), vec![0, 255, 0]);
+        assert_eq!(pages[2].1.dimensions(), (16, 3));
+        assert!(black(&pages[2].1, 0).is_empty());
+        assert_eq!(black(&pages[2].1, 1), vec![4, 5, 6, 7]);
+        assert_eq!(black(&pages[2].1, 2), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_open_pdf_pages() {
+        let path = temp_path("pages.pdf");
+        std::fs::write(&path, crate::pdf::tests::pdf_with_images(vec![
+            crate::pdf::tests::gray_stream(2, 2, vec![0, 255, 255, 0]),
+            crate::pdf::tests::gray_stream(3, 1, vec![255, 255, 255])
+        ])).unwrap();
+
+        let pages = open_pages(&path).unwrap().collect::<Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].1.clone().into_raw(), vec![0, 255, 255, 0]);
+        assert_eq!((pages[1].0, pages[1].1.dimensions()), (2, (3, 1)));
+    }
+}
@@ -1,287 +1,312 @@
+use crate::error::Result;
+use crate::matrix::Matrix;
 
+/**
+Linear Kalman filter with N state variables and M measured variables.
 
-pub type M2x1 = (
-    (f32,),
-    (f32,)
-);
-pub type M2x2 = (
-    (f32, f32),
-    (f32, f32)
-);
+x : The mean state estimate.
+p : The state covariance.
+f : The state transition matrix.
+q : The process noise covariance matrix.
+h : The observation matrix, mapping a state to a measurement.
+r : The measurement noise covariance matrix.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KalmanFilter<const N: usize, const M: usize> {
+    pub x: Matrix<N, 1>,
+    pub p: Matrix<N, N>,
+    pub f: Matrix<N, N>,
+    pub q: Matrix<N, N>,
+    pub h: Matrix<M, N>,
+    pub r: Matrix<M, M>
+}
 
+impl<const N: usize, const M: usize> KalmanFilter<N, M> {
 
-fn dot_2x2_2x1(a: &M2x2, b: &M2x1) -> M2x1 {
-    (
-        (a.0.0 * b.0.0 + a.0.1 * b.1.0, ), 
-        (a.1.0 * b.0.0 + a.1.1 * b.1.0, )
-    )
-}
+    pub fn predict(&mut self) {
+        self.x = self.f * self.x;
+        self.p = self.f * self.p * self.f.transpose() + self.q;
+    }
 
-fn dot_2x2(a: &M2x2, b: &M2x2) -> M2x2 {
-    (
-        (a.0.0 * b.0.0 + a.0.1 * b.1.0, a.0.0 * b.0.1 + a.0.1 * b.1.1), 
-        (a.1.0 * b.0.0 + a.1.1 * b.1.0, a.1.0 * b.0.1 + a.1.1 * b.1.1)
-    )
-}
+    /**
+    Same as `predict`, but drops the covariance between the state variables.
+    The resulting covariance no longer matches the filter error, only use it to reproduce the former tracker.
+    */
+    pub fn predict_diagonal(&mut self) {
+        self.predict();
+        for i in 0..N {
+            for j in 0..N {
+                if i != j {self.p.0[i][j] = 0.0;}
+            }
+        }
+    }
 
-fn transpose(a: &M2x2) -> M2x2 {
-    (
-        (a.0.0, a.1.0),
-        (a.0.1, a.1.1)
-    )
-}
+    /**
+    z : The measurement of the current step.
+    */
+    pub fn update(&mut self, z: &Matrix<M, 1>) -> Result<()> {
+        let s = self.h * self.p * self.h.transpose() + self.r;
+        let k = self.p * self.h.transpose() * s.inverse()?;
 
-fn add_2x2(a: &M2x2, b: &M2x2) -> M2x2 {
-    ((a.0.0 + b.0.0, a.0.1 + b.0.1), (a.1.0 + b.1.0, a.1.1 + b.1.1))
-}
+        self.x = self.x + k * (*z - self.h * self.x);
+        self.p = (Matrix::identity() - k * self.h) * self.p;
+        Ok(())
+    }
 
-fn add_2x1(a: &M2x1, b: &M2x1) -> M2x1 {
-    ((a.0.0 + b.0.0, ), (a.1.0 + b.1.0, ))
 }
 
-fn sub_2x1(a: &M2x1, b: &M2x1) -> M2x1 {
-    ((a.0.0 - b.0.0, ), (a.1.0 - b.1.0, ))
+/**
+Position and speed, both measured, as run by `simulator::simulate`.
+*/
+pub fn constant_velocity_transition(dt: f32) -> Matrix<2, 2> {
+    Matrix([[1.0, dt], [0.0, 1.0]])
 }
 
-fn sub_2x2(a: &M2x2, b: &M2x2) -> M2x2 {
-    ((a.0.0 - b.0.0, a.0.1 - b.0.1), (a.1.0 - b.1.0, a.1.1 - b.1.1))
+/**
+Position, speed and acceleration, as tracked by `staves::MotionModel::ConstantAcceleration`.
+*/
+pub fn constant_acceleration_transition(dt: f32) -> Matrix<3, 3> {
+    Matrix([
+        [1.0, dt, dt * dt / 2.0],
+        [0.0, 1.0, dt],
+        [0.0, 0.0, 1.0]
+    ])
 }
 
-fn inv_2x2(a: &M2x2) -> M2x2 {
-    let det = a.0.0 * a.1.1 - a.1.0 * a.0.1;
-    
-    if det == 0.0 {panic!("Could not inverse matrix with determinant equal to zero");}
+impl KalmanFilter<2, 2> {
+
+    /**
+    The line model of `simulator::simulate`: identity covariances, no process noise.
+    */
+    pub fn constant_velocity(position: f32, dt: f32) -> KalmanFilter<2, 2> {
+        KalmanFilter {
+            x: Matrix([[position], [0.0]]),
+            p: Matrix::identity(),
+            f: constant_velocity_transition(dt),
+            q: Matrix::zeros(),
+            h: Matrix::identity(),
+            r: Matrix::identity()
+        }
+    }
 
-    (
-        (a.1.1 / det, -a.0.1 / det),
-        (-a.1.0 / det, a.0.0 / det)
-    )
 }
 
 /**
-x : The mean state estimate of the previous step (k −1)
-p : The state covariance of previous step (k −1).
-a : The transition n n × matrix.
-*/
+Rauch–Tung–Striebel smoother, refining every filtered estimate with the later measurements.
 
-pub fn predict(x: &M2x1, p: &M2x2, a: &M2x2) -> (M2x1, M2x2) {    
-    let x = dot_2x2_2x1(&a, &x);
-    let p = dot_2x2(&a, &dot_2x2(&p, &transpose(&a)));
-    let p_diag = (
-        (p.0.0, 0.0),
-        (0.0, p.1.1)
-    );
-    (x, p_diag)
-}
+filtered : The state estimate and covariance after the update of every step.
+predicted : The state estimate and covariance predicted for every step but the first one.
+f : The transition matrix that led to every step but the first one.
 
-/**
-x : The mean state estimate of the previous step (k −1).
-p : The state covariance of previous step (k −1).
-y : The measurement matrix.
-h : The state matrix.
-r : The measurement noise covariance matrix.
+Returns the smoothed state estimate and covariance of every step.
 */
-pub fn update(x: &M2x1, p: &M2x2, y: &M2x1, h: &M2x2, r: &M2x2) -> (M2x1, M2x2) {
-    let k_num = dot_2x2(&p, &transpose(&h));
-    let k_den =
-        &add_2x2(
-            &dot_2x2(
-                &dot_2x2(&h, &p), 
-                &transpose(&h)
-            ), 
-            &r
-        );
+pub fn smooth<const N: usize>(
+    filtered: &[(Matrix<N, 1>, Matrix<N, N>)],
+    predicted: &[(Matrix<N, 1>, Matrix<N, N>)],
+    f: &[Matrix<N, N>]
+) -> Result<Vec<(Matrix<N, 1>, Matrix<N, N>)>> {
+    let mut smoothed = filtered.to_vec();
+
+    for k in (0..filtered.len().saturating_sub(1)).rev() {
+        let (x, p) = filtered[k];
+        let (x_pred, p_pred) = predicted[k];
+        let (x_next, p_next) = smoothed[k + 1];
+
+        let c = p * f[k].transpose() * p_pred.inverse()?;
 
-    let k = dot_2x2(&k_num, &inv_2x2(&k_den));
-
-    let x = add_2x1(
-        &x, 
-        &dot_2x2_2x1(
-            &k, 
-            &sub_2x1(
-                &y, 
-                &dot_2x2_2x1(&h, &x)
-            )
-        )
-    );
-
-    let p = sub_2x2(
-        &p,
-        &dot_2x2(
-            &k,
-            &dot_2x2(&h, &p)
-        )
-    );
-    (x, p)
+        smoothed[k] = (x + c * (x_next - x_pred), p + c * (p_next - p_pred) * c.transpose());
+    }
+
+    Ok(smoothed)
 }
 
 #[cfg(test)]
 mod test {
 
-    use super::{predict, dot_2x2_2x1, dot_2x2, transpose, add_2x2, sub_2x1, add_2x1, sub_2x2, inv_2x2};
+    use super::{smooth, constant_acceleration_transition, KalmanFilter};
+    use crate::error::Error;
+    use crate::matrix::Matrix;
 
-    #[test]
-    fn test_transpose() {
-        let a = (
-            (1.0, 2.0),
-            (3.0, 4.0)
-        );
-        let res = (
-            (1.0, 3.0),
-            (2.0, 4.0)
-        );
-        assert_eq!(transpose(&a), res);
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn filter(x: Matrix<2, 1>, p: Matrix<2, 2>, f: Matrix<2, 2>, q: Matrix<2, 2>) -> KalmanFilter<2, 2> {
+        KalmanFilter { x, p, f, q, h: Matrix::identity(), r: Matrix::identity() }
     }
 
     #[test]
-    fn test_dot_to_2x1() {
-        let a = (
-            (1.0, 2.0),
-            (3.0, 4.0)
-        );
-        let b = (
-            (5.0,),
-            (6.0,)
-        );
-        let res = (
-            (17.0,),
-            (39.0,)
+    fn test_predict_diagonal() {
+        let mut kf = filter(
+            Matrix([[1.0], [2.0]]),
+            Matrix([[3.0, 0.0], [0.0, 4.0]]),
+            Matrix([[2.0, 2.0], [0.0, 2.0]]),
+            Matrix([[0.5, 0.0], [0.0, 0.25]])
         );
-        assert_eq!(dot_2x2_2x1(&a, &b), res);
+
+        kf.predict_diagonal();
+
+        assert_eq!(kf.x, Matrix([[6.0], [4.0]]));
+        assert_eq!(kf.p, Matrix([[28.5, 0.0], [0.0, 16.25]]));
     }
 
     #[test]
-    fn test_dot_to_2x2() {
-        let a = (
-            (1.0, 2.0),
-            (3.0, 4.0)
-        );
-        let b = (
-            (5.0, 6.0),
-            (7.0, 8.0)
-        );
-        let res = (
-            (19.0, 22.0),
-            (43.0, 50.0)
+    fn test_predict() {
+        let mut kf = filter(
+            Matrix([[1.0], [2.0]]),
+            Matrix([[3.0, 0.0], [0.0, 4.0]]),
+            Matrix([[2.0, 2.0], [0.0, 2.0]]),
+            Matrix([[0.5, 0.0], [0.0, 0.25]])
         );
-        assert_eq!(dot_2x2(&a, &b), res);
+
+        kf.predict();
+
+        assert_eq!(kf.x, Matrix([[6.0], [4.0]]));
+        assert_eq!(kf.p, Matrix([[28.5, 16.0], [16.0, 16.25]]));
     }
 
     #[test]
-    fn test_add_2x2() {
-        let a = (
-            (1.0, 2.0),
-            (3.0, 4.0)
-        );
-        let b = (
-            (5.0, 6.0),
-            (7.0, 8.0)
-        );
-        let res = (
-            (6.0, 8.0),
-            (10.0, 12.0)
-        );
-        assert_eq!(add_2x2(&a, &b), res);
+    fn test_update() {
+        let mut kf = KalmanFilter::constant_velocity(1.0, 1.0);
+        kf.x = Matrix([[1.0], [0.5]]);
+        kf.p = Matrix([[2.0, 0.0], [0.0, 3.0]]);
+
+        kf.update(&Matrix([[2.0], [1.0]])).unwrap();
+
+        assert!((kf.x.0[0][0] - 5.0 / 3.0).abs() < 1e-6 && (kf.x.0[1][0] - 0.875).abs() < 1e-6);
+        assert!((kf.p.0[0][0] - 2.0 / 3.0).abs() < 1e-6 && (kf.p.0[1][1] - 0.75).abs() < 1e-6);
     }
-    
-    #[test]
-    fn test_sub_2x1() {
-        let a = (
-            (1.0,),
-            (2.0,)
-        );
-        let b = (
-            (3.0,),
-            (4.0,)
-        );
-        let res = (
-            (-2.0,),
-            (-2.0,)
-        );
-        assert_eq!(sub_2x1(&a, &b), res);
+
+    fn assert_symmetric_psd<const N: usize>(p: &Matrix<N, N>) {
+        let scale = (0..N).map(|i| p.0[i][i].abs()).fold(1.0, f32::max);
+        for i in 0..N {
+            assert!(p.0[i][i] >= 0.0, "negative variance: {:?}", p);
+            for j in 0..N {
+                assert!((p.0[i][j] - p.0[j][i]).abs() <= 1e-4 * scale, "not symmetric: {:?}", p);
+            }
+        }
+        assert!(p.cholesky().is_ok() || p.0.iter().flatten().all(|v| v.abs() < 1e-6), "not positive definite: {:?}", p);
     }
 
     #[test]
-    fn test_add_2x1() {
-        let a = (
-            (1.0,),
-            (2.0,)
-        );
-        let b = (
-            (3.0,),
-            (4.0,)
-        );
-        let res = (
-            (4.0,),
-            (6.0,)
-        );
-        assert_eq!(add_2x1(&a, &b), res);
+    fn test_covariance_stay_symmetric_psd() {
+        let noises = [Matrix::zeros(), Matrix::diagonal([0.01, 0.001]), Matrix([[1.0, 0.5], [0.5, 1.0]])];
+
+        for q in &noises {
+            for seed in 0..20 {
+                let line = crate::simulator::sample_line_gen(&mut StdRng::seed_from_u64(seed));
+                let mut kf = KalmanFilter { q: *q, ..KalmanFilter::constant_velocity(line[0], 1.0) };
+
+                for w in line.windows(2) {
+                    kf.predict();
+                    assert_symmetric_psd(&kf.p);
+
+                    kf.update(&Matrix([[w[1]], [w[1] - w[0]]])).unwrap();
+                    assert_symmetric_psd(&kf.p);
+                }
+            }
+        }
     }
-    #[test]
-    fn test_sub_2x2() {
-        let a = (
-            (1.0, 2.0),
-            (3.0, 4.0)
-        );
-        let b = (
-            (5.0, 6.0),
-            (7.0, 8.0)
-        );
-        let res = (
-            (-4.0, -4.0),
-            (-4.0, -4.0)
-        );
-        assert_eq!(sub_2x2(&a, &b), res);
+
+    fn constant_acceleration(position: f32, q: f32) -> KalmanFilter<3, 1> {
+        let g = Matrix([[0.5], [1.0], [1.0]]);
+        KalmanFilter {
+            x: Matrix([[position], [0.0], [0.0]]),
+            p: Matrix::identity(),
+            f: constant_acceleration_transition(1.0),
+            q: (g * g.transpose()).scale(q),
+            h: Matrix([[1.0, 0.0, 0.0]]),
+            r: Matrix::identity()
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn test_inv_2x2_should_panic_if_determinant_is_0() {
-        let a = (
-            (1.0, 1.0),
-            (2.0, 2.0)
-        );        
-        inv_2x2(&a);
+    fn test_position_only_covariance_stay_symmetric_psd() {
+        for seed in 0..20 {
+            let line = crate::simulator::sample_line_gen(&mut StdRng::seed_from_u64(seed));
+            let mut kf = constant_acceleration(line[0], 1e-3);
+
+            for z in &line[1..] {
+                kf.predict();
+                kf.update(&Matrix([[*z]])).unwrap();
+                assert_symmetric_psd(&kf.p);
+            }
+        }
     }
 
     #[test]
-    fn test_inv_2x2() {
-        let a = (
-            (5.0, 4.0),
-            (2.0, 2.0)
-        );  
-        let res = (
-            (1.0, -2.0),
-            (-1.0, 2.5)
-        ); 
-        assert_eq!(inv_2x2(&a), res);
+    fn test_constant_velocity_predict_keep_full_covariance() {
+        let mut kf = KalmanFilter::constant_velocity(1.0, 2.0);
+        kf.x = Matrix([[1.0], [2.0]]);
+        kf.p = Matrix([[3.0, 0.0], [0.0, 4.0]]);
+
+        kf.predict();
+
+        assert_eq!(kf.x, Matrix([[5.0], [2.0]]));
+        assert_eq!(kf.p, Matrix([[19.0, 8.0], [8.0, 4.0]]));
     }
-    
 
     #[test]
-    fn test_predict() {
+    fn test_constant_acceleration_track_curve() {
+        let curve = |t: f32| 10.0 + 0.3 * t + 0.02 * t * t;
+
+        let mut curved = constant_acceleration(curve(0.0), 1e-4);
+        let mut straight = KalmanFilter::constant_velocity(curve(0.0), 1.0);
+
+        for t in 1..60 {
+            let z = curve(t as f32);
+            curved.predict();
+            curved.update(&Matrix([[z]])).unwrap();
+            straight.predict();
+            straight.update(&Matrix([[z], [z - curve(t as f32 - 1.0)]])).unwrap();
+        }
+
+        let curved_error = (curved.x.0[0][0] - curve(59.0)).abs();
+        let straight_error = (straight.x.0[0][0] - curve(59.0)).abs();
+        assert!(curved_error < 0.1, "{}", curved_error);
+        assert!(curved_error < straight_error);
+    }
 
-        let x = (
-            (1.0,),
-            (2.0,)
-        );
+    #[test]
+    fn test_update_should_fail_on_singular_innovation() {
+        let mut kf = constant_acceleration(1.0, 0.0);
+        kf.p = Matrix::zeros();
+        kf.r = Matrix::zeros();
 
-        let p = (
-            (3.0, 0.0),
-            (0.0, 4.0)
-        );
+        assert!(matches!(kf.update(&Matrix([[1.0]])), Err(Error::SingularCovariance)));
+    }
 
-        let a = (
-            (2.0, 2.0),
-            (0.0, 2.0)
-        );
-        let res = (
-            ((6.0,), (4.0,)),
-            ((28.0, 0.0), (0.0, 16.0)),
-        );
+    #[test]
+    fn test_smooth_refine_first_estimates() {
+        let line = (0..40).map(|k| 10.0 + 0.25 * k as f32).collect::<Vec<f32>>();
+        let mut kf = KalmanFilter { q: Matrix::diagonal([0.0, 0.01]), ..KalmanFilter::constant_velocity(line[0], 1.0) };
+
+        let mut filtered = vec![(kf.x, kf.p)];
+        let mut predicted = Vec::new();
+        for k in 1..line.len() {
+            kf.predict();
+            predicted.push((kf.x, kf.p));
+            kf.update(&Matrix([[line[k]], [line[k] - line[k - 1]]])).unwrap();
+            filtered.push((kf.x, kf.p));
+        }
+
+        let smoothed = smooth(&filtered, &predicted, &vec![kf.f; predicted.len()]).unwrap();
+
+        assert_eq!(smoothed.len(), line.len());
+        assert_eq!(smoothed.last(), filtered.last());
+        assert!((smoothed[0].0.0[1][0] - 0.25).abs() < (filtered[0].0.0[1][0] - 0.25).abs());
+        assert!(smoothed[0].1.0[0][0] < filtered[0].1.0[0][0]);
+        let error = |states: &Vec<(Matrix<2, 1>, Matrix<2, 2>)>| states[..5]
+            .iter()
+            .zip(&line)
+            .map(|(s, x)| (s.0.0[0][0] - x).abs())
+            .sum::<f32>();
+        assert!(error(&smoothed) < error(&filtered));
+    }
 
-        assert_eq!(predict(&x, &p, &a), res);
+    #[test]
+    fn test_smooth_single_step() {
+        let state = (Matrix([[1.0], [0.0]]), Matrix::<2, 2>::identity());
+        assert_eq!(smooth(&[state], &[], &[]).unwrap(), vec![state]);
+        assert_eq!(smooth::<2>(&[], &[], &[]).unwrap(), vec![]);
     }
 }
-    
-
@@ -0,0 +1,231 @@
+/*!
+Staff line detection for scanned music scores.
+
+```
+use rustscanscore::{Scanner, ScanConfig};
+
+let img = image::open("score_sample/score_sample1.png").unwrap();
+let result = Scanner::new(ScanConfig::default()).scan_image(&img).unwrap();
+
+assert_eq!(result.groups.len(), 2);
+```
+*/
+
+pub mod analysis;
+pub mod assignment;
+pub mod barlines;
+pub mod binarize;
+pub mod ccitt;
+pub mod degradation;
+pub mod deskew;
+pub mod error;
+pub mod evaluation;
+pub mod input;
+pub mod kalman;
+pub mod matrix;
+pub mod pdf;
+pub mod removal;
+pub mod render;
+pub mod report;
+pub mod scanner;
+pub mod simulator;
+pub mod staves;
+pub mod synthetic;
+pub mod systems;
+
+pub use error::{Error, Result};
+pub use scanner::{ScanConfig, ScanResult, Scanner};
+
+/**
+Index of a pixel once the buffer is transposed.
+
+id : The pixel index in a buffer made of chunks of `a` pixels.
+a : The chunk length of the source buffer, the width for a row-major image.
+b : The chunk length of the transposed buffer, the height for a row-major image.
+*/
+pub fn buffer_id_swap(id: usize, a: usize, b: usize) -> usize {
+    let dim1 = id % a;
+    let dim2 = id / a;
+    dim1 * b + dim2
+}
+
+/**
+Transposes a row-major grayscale image into the column-major buffer used by `staves::detect_staves`.
+*/
+pub fn to_column_major(img: &image::GrayImage) -> Vec<u8> {
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+    let mut buffer_vertical = vec![255; width * height];
+
+    for (id_horizontal, v) in img.iter().enumerate() {
+        buffer_vertical[buffer_id_swap(id_horizontal, width, height)] = *v;
+    }
+
+    buffer_vertical
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    pub fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn prepare_img(img: &str, method: &binarize::Method) -> error::Result<(Vec<u8>, usize, usize)> {
+        let img_gray = input::open_luma(img)?;
+    
+        let width = img_gray.width() as usize;
+        let height = img_gray.height() as usize;
+        let buffer_vertical = to_column_major(&img_gray);
+
+        Ok((binarize::binarize(&buffer_vertical, height, method), width, height))
+    }
+
+    #[test]
+    fn test_buffer_idx_swap_first_one_last_one_keep_same() {
+        let height = 5;
+        let width = 10;
+        let idx_first = 0;
+        let idx_last = 49;
+        assert_eq!(buffer_id_swap(idx_first, width, height), idx_first);
+        assert_eq!(buffer_id_swap(idx_last, height, width), idx_last);
+    }
+    #[test]
+    fn test_buffer_idx_swap_point_translation_is_reversible() {
+        let height = 5;
+        let width = 10;
+        let idx_height = 9;
+        let idx_width = 45;
+        assert_eq!(buffer_id_swap(idx_height, width, height), idx_width);
+        assert_eq!(buffer_id_swap(idx_width, height, width), idx_height);
+    }
+
+    #[test]
+    fn test_to_column_major() {
+        let img = image::GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(to_column_major(&img), vec![1, 4, 2, 5, 3, 6]);
+    }
+
+    #[test]
+    fn test_one_full_line_get_one_staff_with_10_items() {
+        let (buffer, _, height) = prepare_img("score_sample/single_line_top.png", &binarize::Method::Otsu).unwrap();
+
+        let staves = staves::detect_staves(buffer, height).unwrap();
+        
+        assert_eq!(staves.len(), 1);
+        assert_eq!(staves[0].buffer.len(), 10);
+    }
+
+    #[test]
+    fn test_full_black_handled_correctly() {
+        let (buffer, _, height) = prepare_img("score_sample/full_black.png", &binarize::Method::Otsu).unwrap();
+
+        let staves = staves::detect_staves(buffer, height).unwrap();
+
+        assert_eq!(staves.len(), 1);
+        assert_eq!(staves[0].buffer.len(), 10);
+        assert_eq!(staves[0].buffer[0].0.len(), 10);
+
+    }
+
+    #[test]
+    fn test_2px_line_with_holes() {
+        let (buffer, _, height) = prepare_img("score_sample/2px_line_with_holes.png", &binarize::Method::Otsu).unwrap();
+
+        let staves = staves::detect_staves(buffer, height).unwrap();
+
+        assert_eq!(staves.len(), 1);
+
+    }
+
+    #[test]
+    fn test_2px_line_curved() {
+        let (buffer, _, height) = prepare_img("score_sample/2px_line_curved.png", &binarize::Method::Otsu).unwrap();
+
+        let staves = staves::detect_staves(buffer, height).unwrap();
+
+        assert_eq!(staves.len(), 1);
+
+
+    }
+
+    #[test]
+    fn test_grayscale_scan_needs_binarization() {
+        let (buffer, _, height) = prepare_img("score_sample/score_sample1.png", &binarize::Method::Otsu).unwrap();
+        let otsu = staves::detect_staves(buffer, height).unwrap();
+
+        let (buffer, _, height) = prepare_img("score_sample/score_sample1.png", &binarize::Method::sauvola()).unwrap();
+        let sauvola = staves::detect_staves(buffer, height).unwrap();
+
+        assert!(otsu.iter().filter(|s| s.buffer.len() > 300).count() >= 5);
+        assert!(sauvola.iter().filter(|s| s.buffer.len() > 300).count() >= 5);
+    }
+
+    #[test]
+    fn test_score_sample_staff_systems() {
+        let (buffer, _, height) = prepare_img("score_sample/score_sample1.png", &binarize::Method::Otsu).unwrap();
+        let metrics = analysis::analyse(&buffer, height).unwrap();
+        let staves = staves::detect_staves_with(buffer, height, &metrics.tracker_config()).unwrap();
+
+        let groups = systems::group_staves(&staves);
+
+        assert_eq!(groups.len(), 2);
+        for group in groups {
+            match group {
+                systems::StaffGroup::Staff(s) => assert!((s.spacing - 8.0).abs() < 0.5),
+                g => panic!("Unexpected group {:?}", g)
+            }
+        }
+    }
+
+    #[test]
+    fn test_score_sample_metrics_reduce_noise_tracks() {
+        let (buffer, _, height) = prepare_img("score_sample/score_sample1.png", &binarize::Method::Otsu).unwrap();
+        let metrics = analysis::analyse(&buffer, height).unwrap();
+
+        assert_eq!(metrics, analysis::PageMetrics { staffline_height: 2, staffspace_height: 6 });
+
+        let tuned = staves::detect_staves_with(buffer.clone(), height, &metrics.tracker_config()).unwrap();
+        let default = staves::detect_staves(buffer, height).unwrap();
+
+        assert!(tuned.len() < default.len());
+        assert_eq!(tuned.len(), 10);
+    }
+
+    #[test]
+    fn test_score_sample_staff_removal() {
+        let (buffer, _, height) = prepare_img("score_sample/score_sample1.png", &binarize::Method::Otsu).unwrap();
+        let metrics = analysis::analyse(&buffer, height).unwrap();
+        let staves = staves::detect_staves_with(buffer.clone(), height, &metrics.tracker_config()).unwrap();
+
+        let removal = removal::remove_staves(&buffer, height, &staves, metrics.staffline_height);
+
+        let ink = |b: &Vec<u8>| b.iter().filter(|v| **v == 0).count();
+        assert_eq!(ink(&removal.image) + ink(&removal.mask), ink(&buffer));
+        assert!(ink(&removal.mask) > ink(&removal.image));
+        assert!(analysis::analyse(&removal.image, height).unwrap().staffline_height > metrics.staffline_height);
+    }
+
+    #[test]
+    fn test_crossed_lines() {
+        init_logger();
+        let (buffer, _, height) = prepare_img("score_sample/crossed_lines.png", &binarize::Method::Otsu).unwrap();
+
+        let staves = staves::detect_staves(buffer, height).unwrap();
+
+        assert_eq!(staves.len(), 2);
+        assert!(staves.iter().all(|s| (s.start(), s.end()) == (1, 10)));
+        assert!(staves.iter().any(|s| s.centres().iter().all(|(_, x)| (x - 5.5).abs() < 1.5)));
+
+        let img = input::open_luma("score_sample/crossed_lines.png").unwrap();
+        let overlay = render::overlay(&img, &staves);
+
+        // Both tracks are drawn in colour over the grey page.
+        assert_eq!(overlay.dimensions(), img.dimensions());
+        assert!(overlay.pixels().any(|p| p.0[0] != p.0[1] || p.0[1] != p.0[2]));
+
+    }
+
+}
@@ -1,137 +1,660 @@
-use std::usize;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use image::GrayImage;
+use log::{warn, LevelFilter};
+
+use rustscanscore::binarize::Method;
+use rustscanscore::degradation::{Degradation, Pipeline};
+use rustscanscore::report::Report;
+use rustscanscore::evaluation::{evaluate_staves, Evaluation};
+use rustscanscore::synthetic::{GroundTruth, PageConfig};
+use rustscanscore::systems::StaffGroup;
+use rustscanscore::{ScanConfig, ScanResult, Scanner};
+
+#[derive(Debug, Parser)]
+#[command(name = "rustscanscore", version, about = "Staff line detection for scanned music scores")]
+struct Cli {
+    /// Increase logging, repeat for more (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command
+}
 
-mod staves;
-mod kalman;
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the lines and staves detected on every page of an image, a multi-page TIFF or a PDF
+    Detect {
+        image: PathBuf,
+
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// Output format, a JSON array of page reports, even for a single page
+        #[arg(short, long, value_enum, default_value_t = Format::Text)]
+        format: Format
+    },
+    /// Draw the detected lines over a page
+    Overlay {
+        image: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// 1-based page of a multi-page TIFF or a PDF
+        #[arg(short, long, default_value_t = 1)]
+        page: usize,
+
+        #[command(flatten)]
+        scan: ScanArgs
+    },
+    /// Compare the lines detected on pages to their ground truth, saved next to each page as JSON by `generate`
+    ///
+    /// Pages are scanned without deskewing, whatever their skew: the ground truth lines are given on the page
+    /// as generated, and the tracks of a straightened page would be compared in the rotated coordinates.
+    Evaluate {
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+
+        /// Largest vertical distance in pixel between a track and a truth line to match
+        #[arg(long, default_value_t = 1.0)]
+        max_distance: f32,
+
+        #[command(flatten)]
+        threshold: ThresholdArgs,
+
+        #[arg(short, long, value_enum, default_value_t = EvaluationFormat::Text)]
+        format: EvaluationFormat
+    },
+    /// Render a synthetic page and save its ground truth lines next to it, as JSON
+    Generate {
+        #[arg(short, long, default_value = "synthetic_page.png")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        page: PageArgs,
+
+        #[command(flatten)]
+        degradation: DegradationArgs
+    },
+    /// Give an image the defects of a real scan
+    Degrade {
+        image: PathBuf,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        degradation: DegradationArgs,
+
+        #[arg(long, default_value_t = 0)]
+        seed: u64
+    },
+    /// Run the Kalman filter over a simulated line
+    Simulate {
+        #[arg(short, long, default_value = "simulated_kalman_filter.png")]
+        output: PathBuf,
+
+        #[arg(long, default_value_t = 0)]
+        seed: u64
+    }
+}
 
+#[derive(Debug, clap::Args)]
+struct ScanArgs {
+    #[command(flatten)]
+    threshold: ThresholdArgs,
 
+    /// Keep the page as is instead of straightening it
+    #[arg(long)]
+    no_deskew: bool
+}
 
+#[derive(Debug, clap::Args)]
+struct ThresholdArgs {
+    /// Binarization method
+    #[arg(short, long, value_enum, default_value_t = Threshold::Otsu)]
+    threshold: Threshold,
 
-fn main() {
+    /// Window size in pixel of the local thresholds
+    #[arg(long)]
+    window: Option<usize>,
 
-    /*
-    for (id, y) in buffer_x.iter().enumerate() {
-        buffer_y[buffer_id_swap(id, height, width)] = *y;
-    }
+    /// k parameter of the local thresholds
+    #[arg(long, allow_negative_numbers = true)]
+    k: Option<f32>
+}
 
-    image::save_buffer("score_sample/binary_score.png", &buffer_y, width as u32, height as u32, image::ColorType::L8).unwrap();
-    
-    
-    match simulator::line() {
-        Err(e) => println!("{:?}", e),
-        _ => ()
-    };
-    */
+#[derive(Debug, clap::Args)]
+struct PageArgs {
+    /// Page width in pixel
+    #[arg(long, default_value_t = 800)]
+    width: usize,
 
-}
+    /// Page height in pixel
+    #[arg(long, default_value_t = 600)]
+    height: usize,
+
+    /// Number of staves on the page
+    #[arg(long, default_value_t = 3)]
+    systems: usize,
+
+    /// Number of lines of each staff
+    #[arg(long, default_value_t = 5)]
+    lines: usize,
+
+    /// Distance in pixel between two line centres
+    #[arg(long, default_value_t = 12.0)]
+    spacing: f32,
+
+    /// Line thickness in pixel
+    #[arg(long, default_value_t = 2.0)]
+    thickness: f32,
+
+    /// Line slope in degree
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    skew: f32,
 
+    /// Vertical bow of the lines in pixel
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    curvature: f32,
 
-fn buffer_id_swap(id: usize, a: usize, b: usize) -> usize {
-    let dim1 = id % a;
-    let dim2 = id / a;
-    dim1 * b + dim2
+    /// Number of holes in every line
+    #[arg(long, default_value_t = 0)]
+    gaps: usize,
+
+    /// Number of slurs crossing every staff
+    #[arg(long, default_value_t = 0)]
+    slurs: usize,
+
+    /// Probability of every pixel to be flipped
+    #[arg(long, default_value_t = 0.0)]
+    noise: f64,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64
 }
 
-#[cfg(test)]
-mod tests {
+// Scan defects, applied in the order of a real page: printing, reverse page, lighting, dust, then compression.
+#[derive(Debug, clap::Args)]
+struct DegradationArgs {
+    /// Add Kanungo edge noise to the ink
+    #[arg(long)]
+    kanungo: bool,
 
-    use super::*;
+    /// Opacity of the reverse page showing through
+    #[arg(long)]
+    bleed_through: Option<f32>,
+
+    /// Fall of the light across the page, from 0 to 1
+    #[arg(long)]
+    illumination: Option<f32>,
+
+    /// Fall of the light in the corners, from 0 to 1
+    #[arg(long)]
+    vignette: Option<f32>,
+
+    /// Probability of every pixel to turn white
+    #[arg(long)]
+    salt: Option<f64>,
+
+    /// Probability of every pixel to turn black
+    #[arg(long)]
+    pepper: Option<f64>,
+
+    /// Save through a JPEG encoding of this quality, from 1 to 100
+    #[arg(long)]
+    jpeg: Option<u8>
+}
 
-    pub fn init_logger() {
-        let _ = env_logger::builder().is_test(true).try_init();
-    }
-
-    fn prepare_img(img: &str) -> (Vec<u8>, usize, usize) {
-        let img = image::open(img).unwrap();
-        let img_gray = img.into_luma8();
-    
-        let width = img_gray.width() as usize;
-        let height = img_gray.height() as usize;
-        let buffer_size = width * height;
-    
-    
-        let mut buffer_vertical = vec![255; buffer_size];
-    
-      
-        for id_horizontal in 0..buffer_size {
-            let id_vertical = buffer_id_swap(id_horizontal, width, height);
-            buffer_vertical[id_vertical] = *img_gray.get(id_horizontal).unwrap_or(&255);
+impl DegradationArgs {
+
+    fn pipeline(&self, seed: u64) -> Pipeline {
+        let mut pipeline = Pipeline::new(seed);
+        if self.kanungo {
+            pipeline = pipeline.then(Degradation::kanungo());
+        }
+        if let Some(strength) = self.bleed_through {
+            pipeline = pipeline.then(Degradation::BleedThrough { strength, blur: 1.5 });
+        }
+        if self.illumination.is_some() || self.vignette.is_some() {
+            pipeline = pipeline.then(Degradation::Illumination {
+                gradient: self.illumination.unwrap_or(0.0),
+                vignette: self.vignette.unwrap_or(0.0)
+            });
         }
+        if self.salt.is_some() || self.pepper.is_some() {
+            pipeline = pipeline.then(Degradation::SaltAndPepper {
+                salt: self.salt.unwrap_or(0.0),
+                pepper: self.pepper.unwrap_or(0.0)
+            });
+        }
+        if let Some(quality) = self.jpeg {
+            pipeline = pipeline.then(Degradation::Jpeg { quality });
+        }
+        pipeline
+    }
 
-        (buffer_vertical, width, height)
+}
+
+impl PageArgs {
+
+    fn config(&self) -> PageConfig {
+        PageConfig {
+            width: self.width,
+            height: self.height,
+            systems: self.systems,
+            lines: self.lines,
+            spacing: self.spacing,
+            thickness: self.thickness,
+            skew: self.skew,
+            curvature: self.curvature,
+            gaps: self.gaps,
+            slurs: self.slurs,
+            noise: self.noise,
+            seed: self.seed,
+            ..PageConfig::default()
+        }
     }
 
-    #[test]
-    fn test_buffer_idx_swap_first_one_last_one_keep_same() {
-        let height = 5;
-        let width = 10;
-        let idx_first = 0;
-        let idx_last = 49;
-        assert_eq!(buffer_id_swap(idx_first, width, height), idx_first);
-        assert_eq!(buffer_id_swap(idx_last, height, width), idx_last);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Threshold {
+    Otsu,
+    Sauvola,
+    Niblack
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Text,
+    Csv,
+    Json
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum EvaluationFormat {
+    Text,
+    Json
+}
+
+impl ThresholdArgs {
+
+    fn method(&self) -> Method {
+        let method = match self.threshold {
+            Threshold::Otsu => Method::Otsu,
+            Threshold::Sauvola => Method::sauvola(),
+            Threshold::Niblack => Method::niblack()
+        };
+        match method {
+            Method::Otsu => Method::Otsu,
+            Method::Sauvola { window, k } => Method::Sauvola {
+                window: self.window.unwrap_or(window),
+                k: self.k.unwrap_or(k)
+            },
+            Method::Niblack { window, k } => Method::Niblack {
+                window: self.window.unwrap_or(window),
+                k: self.k.unwrap_or(k)
+            }
+        }
     }
-    #[test]
-    fn test_buffer_idx_swap_point_translation_is_reversible() {
-        let height = 5;
-        let width = 10;
-        let idx_height = 9;
-        let idx_width = 45;
-        assert_eq!(buffer_id_swap(idx_height, width, height), idx_width);
-        assert_eq!(buffer_id_swap(idx_width, height, width), idx_height);
+
+}
+
+impl ScanArgs {
+
+    fn scanner(&self) -> Scanner {
+        Scanner::new(ScanConfig {
+            binarization: self.threshold.method(),
+            deskew: !self.no_deskew,
+            ..ScanConfig::default()
+        })
     }
 
-    #[test]
-    fn test_one_full_line_get_one_staff_with_10_items() {
-        let (buffer, _, height) = prepare_img("score_sample/single_line_top.png");
+}
 
-        let staves = staves::detect_staves(buffer, height);
-        
-        assert_eq!(staves.len(), 1);
-        assert_eq!(staves[0].buffer.len(), 10);
+fn level(verbose: u8, quiet: bool) -> Option<LevelFilter> {
+    match (quiet, verbose) {
+        (true, _) => Some(LevelFilter::Error),
+        (_, 0) => None,
+        (_, 1) => Some(LevelFilter::Info),
+        (_, 2) => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace)
     }
+}
 
-    #[test]
-    fn test_full_black_handled_correctly() {
-        let (buffer, _, height) = prepare_img("score_sample/full_black.png");
+fn format_text(result: &ScanResult) -> String {
+    let mut lines = vec![format!("page {}: {}x{}, skew {:.2}", result.page, result.width, result.height, result.skew)];
+
+    for (i, group) in result.groups.iter().enumerate() {
+        let (kind, spacing, thickness) = match group {
+            StaffGroup::Staff(s) => ("staff", s.spacing, s.thickness),
+            StaffGroup::Tablature(t) => ("tablature", t.spacing, t.thickness),
+            StaffGroup::Percussion(l) => ("percussion", 0.0, l.thickness)
+        };
+        lines.push(format!(
+            "{} {}: {} lines, spacing {:.2}, thickness {:.2}",
+            kind, i, group.lines().len(), spacing, thickness
+        ));
+        for line in group.lines() {
+            lines.push(format!(
+                "  track {}: columns {}-{}, position {:.2}",
+                line.track, line.start, line.end, line.position
+            ));
+        }
+        let barlines = result.barlines
+            .iter()
+            .filter(|b| b.crosses(i))
+            .map(|b| format!("{} {}-{}", b.kind, b.start, b.end))
+            .collect::<Vec<String>>();
+        if !barlines.is_empty() {
+            lines.push(format!("  barlines: {}", barlines.join(", ")));
+        }
+    }
 
-        let staves = staves::detect_staves(buffer, height);
+    lines.push(format!("{} tracks", result.staves.len()));
+    lines.join("\n")
+}
+
+const CSV_HEADER: &str = "page,track,start,end,position,thickness";
+
+fn csv_rows(result: &ScanResult) -> Vec<String> {
+    result.staves
+        .iter()
+        .enumerate()
+        .map(|(i, staff)| format!(
+            "{},{},{},{},{:.3},{:.3}",
+            result.page, i, staff.start(), staff.end(), staff.position(), staff.thickness()
+        ))
+        .collect()
+}
 
-        assert_eq!(staves.len(), 7);
-        assert_eq!(staves[0].buffer.len(), 10);
-        assert_eq!(staves[6].buffer[0], (vec![10], 3));
-        assert_eq!(*staves[6].buffer.last().unwrap(), (vec![10], 10));
+fn format_json(reports: &[Report]) -> rustscanscore::Result<String> {
+    Ok(serde_json::to_string_pretty(reports)?)
+}
+
+/**
+The page of 1-based index `page`, unreadable pages before and after it being skipped.
+*/
+fn find_page<I>(pages: I, page: usize) -> rustscanscore::Result<GrayImage>
+where I: Iterator<Item = (usize, rustscanscore::Result<(usize, GrayImage)>)> {
+    pages
+        .map(|(i, res)| (i, res.map(|(_, img)| img)))
+        .find(|(i, _)| *i == page)
+        .ok_or_else(|| rustscanscore::Error::UnsupportedImage(format!("no page {}", page)))?
+        .1
+}
 
+fn format_evaluation(pages: usize, evaluation: &Evaluation) -> String {
+    let scores = evaluation.scores();
+    [
+        format!("{} pages, {} truth points", pages, evaluation.truth_points),
+        format!("precision {:.4}, recall {:.4}", scores.precision, scores.recall),
+        format!("mean error {:.3} px", scores.mean_error),
+        format!(
+            "misses {}, false positives {}, id switches {}, fragmentations {}",
+            evaluation.misses, evaluation.false_positives, evaluation.id_switches, evaluation.fragmentations
+        ),
+        format!("MOTA {:.4}", scores.mota)
+    ].join("\n")
+}
+
+fn run(cli: Cli) -> rustscanscore::Result<()> {
+    match cli.command {
+        Command::Detect { image, scan, format } => {
+            // Only the printed output of every page is kept, a failed page being skipped.
+            let mut pages = Vec::new();
+            let mut reports = Vec::new();
+            let (mut scanned, mut failure) = (0, None);
+            for (index, result) in scan.scanner().scan_document(image)? {
+                let result = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        warn!("Skip page {}: {}", index, e);
+                        failure = Some(e);
+                        continue;
+                    }
+                };
+                scanned += 1;
+                match format {
+                    Format::Text => pages.push(format_text(&result)),
+                    Format::Csv => pages.extend(csv_rows(&result)),
+                    Format::Json => reports.push(Report::new(&result))
+                }
+            }
+            if let (0, Some(e)) = (scanned, failure) {
+                return Err(e);
+            }
+            match format {
+                Format::Text => println!("{}", pages.join("\n\n")),
+                Format::Csv => println!("{}", [vec![CSV_HEADER.to_string()], pages].concat().join("\n")),
+                Format::Json => println!("{}", format_json(&reports)?)
+            }
+        },
+        Command::Overlay { image, output, page, scan } => {
+            let mut pages = rustscanscore::input::open_pages(&image)?;
+            let page = find_page(std::iter::from_fn(|| pages.next().map(|p| (pages.index(), p))), page)?;
+            let result = scan.scanner().scan_image(&image::DynamicImage::ImageLuma8(page.clone()))?;
+            // The lines are tracked on the straightened page.
+            let page = match result.skew == 0.0 {
+                true => page,
+                false => rustscanscore::deskew::rotate(&page, result.skew)
+            };
+            rustscanscore::render::save_overlay(&page, &result.staves, output)?;
+        },
+        Command::Evaluate { images, max_distance, threshold, format } => {
+            // Ground truth lines are given on the page as generated, see the subcommand help.
+            let scanner = Scanner::new(ScanConfig {
+                binarization: threshold.method(),
+                deskew: false,
+                ..ScanConfig::default()
+            });
+            let mut total = Evaluation::default();
+            for image in &images {
+                let truth = GroundTruth::open(image)?;
+                let result = scanner.scan_path(image)?;
+                total += evaluate_staves(&truth, &result.staves, max_distance);
+            }
+            match format {
+                EvaluationFormat::Text => println!("{}", format_evaluation(images.len(), &total)),
+                EvaluationFormat::Json => println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+                    "pages": images.len(),
+                    "counts": total,
+                    "scores": total.scores()
+                }))?)
+            }
+        },
+        Command::Generate { output, page, degradation } => {
+            let config = page.config();
+            let mut page = rustscanscore::synthetic::generate(&config);
+            page.image = degradation.pipeline(config.seed).apply(&page.image)?;
+            page.save(output)?;
+        },
+        Command::Degrade { image, output, degradation, seed } => {
+            let img = rustscanscore::input::open_luma(image)?;
+            degradation.pipeline(seed).apply(&img)?.save(output)?;
+        },
+        Command::Simulate { output, seed } => {
+            let simulation = rustscanscore::simulator::line(output, seed)?;
+            for (name, res) in [("noise", simulation.noise()), ("error", simulation.errors()), ("innovation", simulation.innovations())] {
+                println!("{}: mean {:.3}, std dev {:.3}, rms {:.3}, max {:.3}", name, res.mean, res.std_dev, res.rms, res.max);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = level(cli.verbose, cli.quiet) {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn test_2px_line_with_holes() {
-        let (buffer, _, height) = prepare_img("score_sample/2px_line_with_holes.png");
+    fn test_parse_detect() {
+        let cli = Cli::try_parse_from([
+            "rustscanscore", "-vv", "detect", "page.png", "--threshold", "sauvola", "--k", "-0.1", "-f", "csv", "--no-deskew"
+        ]).unwrap();
+
+        assert_eq!(level(cli.verbose, cli.quiet), Some(LevelFilter::Debug));
+        match cli.command {
+            Command::Detect { image, scan, format } => {
+                assert_eq!(image, PathBuf::from("page.png"));
+                assert_eq!(scan.threshold.method(), Method::Sauvola { window: 15, k: -0.1 });
+                assert!(!scan.scanner().config().deskew);
+                assert_eq!(format, Format::Csv);
+            },
+            c => panic!("Unexpected command {:?}", c)
+        }
+    }
 
-        let staves = staves::detect_staves(buffer, height);
+    #[test]
+    fn test_parse_generate() {
+        let cli = Cli::try_parse_from([
+            "rustscanscore", "generate", "-o", "page.png", "--systems", "2", "--skew", "-1.5", "--seed", "3"
+        ]).unwrap();
+
+        match cli.command {
+            Command::Generate { output, page, degradation } => {
+                assert_eq!(output, PathBuf::from("page.png"));
+                assert_eq!(page.config(), PageConfig { systems: 2, skew: -1.5, seed: 3, ..PageConfig::default() });
+                assert_eq!(degradation.pipeline(3), Pipeline::new(3));
+            },
+            c => panic!("Unexpected command {:?}", c)
+        }
+    }
 
-        assert_eq!(staves.len(), 1);
+    #[test]
+    fn test_parse_degrade() {
+        let cli = Cli::try_parse_from([
+            "rustscanscore", "degrade", "page.png", "-o", "scan.png", "--jpeg", "40", "--kanungo", "--vignette", "0.2", "--seed", "9"
+        ]).unwrap();
+
+        match cli.command {
+            Command::Degrade { degradation, seed, .. } => assert_eq!(
+                degradation.pipeline(seed),
+                Pipeline::new(9)
+                    .then(Degradation::kanungo())
+                    .then(Degradation::Illumination { gradient: 0.0, vignette: 0.2 })
+                    .then(Degradation::Jpeg { quality: 40 })
+            ),
+            c => panic!("Unexpected command {:?}", c)
+        }
+    }
 
+    #[test]
+    fn test_evaluate_generated_page() {
+        let path = std::env::temp_dir().join(format!("rustscanscore_{}_evaluate.png", std::process::id()));
+        rustscanscore::synthetic::generate(&PageConfig { width: 300, height: 200, systems: 1, ..PageConfig::default() })
+            .save(&path)
+            .unwrap();
+
+        let cli = Cli::try_parse_from(["rustscanscore".as_ref(), "evaluate".as_ref(), path.as_os_str()]).unwrap();
+        let res = run(cli);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert!(res.is_ok());
+        let text = format_evaluation(1, &Evaluation { truth_points: 10, matches: 10, ..Evaluation::default() });
+        assert!(text.contains("recall 1.0000") && text.ends_with("MOTA 1.0000"));
     }
 
     #[test]
-    fn test_2px_line_curved() {
-        let (buffer, _, height) = prepare_img("score_sample/2px_line_curved.png");
+    fn test_parse_evaluate() {
+        let cli = Cli::try_parse_from(["rustscanscore", "evaluate", "page.png", "-t", "niblack", "-f", "json"]).unwrap();
+
+        match cli.command {
+            Command::Evaluate { threshold, format, .. } => {
+                assert_eq!(threshold.method(), Method::niblack());
+                assert_eq!(format, EvaluationFormat::Json);
+            },
+            c => panic!("Unexpected command {:?}", c)
+        }
+        assert!(Cli::try_parse_from(["rustscanscore", "evaluate", "page.png", "-f", "csv"]).is_err());
+        assert!(Cli::try_parse_from(["rustscanscore", "evaluate", "page.png", "--no-deskew"]).is_err());
+    }
 
-        let staves = staves::detect_staves(buffer, height);
+    #[test]
+    fn test_overlay_skewed_page() {
+        let path = std::env::temp_dir().join(format!("rustscanscore_{}_skewed.png", std::process::id()));
+        let output = path.with_file_name(format!("rustscanscore_{}_overlay.png", std::process::id()));
+        let page = rustscanscore::synthetic::generate(&PageConfig { width: 400, height: 240, systems: 2, skew: 2.0, ..PageConfig::default() });
+        page.image.save(&path).unwrap();
+
+        let cli = Cli::try_parse_from(["rustscanscore".as_ref(), "overlay".as_ref(), path.as_os_str(), "-o".as_ref(), output.as_os_str()]).unwrap();
+        let res = run(cli);
+        let overlay = image::open(&output).unwrap().into_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&output).unwrap();
+
+        // Every line pixel is covered by the colour of its track.
+        res.unwrap();
+        let ink = page.image.pixels().filter(|p| p.0[0] == 0).count();
+        let uncovered = overlay.pixels().filter(|p| p.0[0] < 128 && p.0[0] == p.0[1] && p.0[1] == p.0[2]).count();
+        assert!(uncovered * 10 < ink, "{} of {}", uncovered, ink);
+    }
 
-        assert_eq!(staves.len(), 1);
+    #[test]
+    fn test_find_page_skip_other_failed_pages() {
+        let failed = || Err(rustscanscore::Error::UnsupportedImage("page".to_string()));
+        let pages = || vec![(1, failed()), (2, Ok((2, GrayImage::new(3, 2)))), (3, failed())].into_iter();
 
+        assert_eq!(find_page(pages(), 2).unwrap().dimensions(), (3, 2));
+        assert!(matches!(find_page(pages(), 1), Err(rustscanscore::Error::UnsupportedImage(reason)) if reason == "page"));
+        assert!(matches!(find_page(pages(), 4), Err(rustscanscore::Error::UnsupportedImage(reason)) if reason == "no page 4"));
+    }
 
+    #[test]
+    fn test_overlay_requires_output() {
+        assert!(Cli::try_parse_from(["rustscanscore", "overlay", "page.png"]).is_err());
     }
 
     #[test]
-    fn test_crossed_lines() {
-        init_logger();
-        let (buffer, _, height) = prepare_img("score_sample/crossed_lines.png");
+    fn test_quiet_conflicts_with_verbose() {
+        assert!(Cli::try_parse_from(["rustscanscore", "-q", "-v", "simulate"]).is_err());
+        let cli = Cli::try_parse_from(["rustscanscore", "-q", "simulate"]).unwrap();
+        assert_eq!(level(cli.verbose, cli.quiet), Some(LevelFilter::Error));
+    }
 
-        let staves = staves::detect_staves(buffer, height);
+    #[test]
+    fn test_format_score_sample() {
+        let result = Scanner::default().scan_path("score_sample/score_sample1.png").unwrap();
 
-        println!("{:?}", staves);
+        let text = format_text(&result);
+        assert!(text.starts_with("page 1: 338x149, skew 0.00\nstaff 0: 5 lines"));
+        assert!(text.contains("  barlines: single 21-23, single 201-203, single 331-333\n"));
 
+        let rows = csv_rows(&result);
+        assert_eq!(rows.len(), result.staves.len());
+        assert!(rows[0].starts_with("1,0,"));
+        assert_eq!(rows[0].split(',').count(), CSV_HEADER.split(',').count());
     }
 
+    #[test]
+    fn test_format_json_single_page() {
+        let report = Report::new(&Scanner::default().scan_path("score_sample/score_sample1.png").unwrap());
+
+        let reports: Vec<Report> = serde_json::from_str(&format_json(std::slice::from_ref(&report)).unwrap()).unwrap();
+        assert_eq!(reports, vec![report]);
+    }
 }
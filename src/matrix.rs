@@ -0,0 +1,242 @@
+use std::ops::{Add, Mul, Sub};
+
+use crate::error::{Error, Result};
+
+/**
+Row-major matrix of R rows and C columns.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix<const R: usize, const C: usize>(pub [[f32; C]; R]);
+
+impl<const R: usize, const C: usize> Matrix<R, C> {
+
+    pub fn zeros() -> Matrix<R, C> {
+        Matrix([[0.0; C]; R])
+    }
+
+    pub fn transpose(&self) -> Matrix<C, R> {
+        let mut res = Matrix::<C, R>::zeros();
+        for i in 0..R {
+            for j in 0..C {
+                res.0[j][i] = self.0[i][j];
+            }
+        }
+        res
+    }
+
+    pub fn scale(&self, k: f32) -> Matrix<R, C> {
+        let mut res = *self;
+        res.0.iter_mut().flatten().for_each(|v| *v *= k);
+        res
+    }
+
+}
+
+impl<const N: usize> Matrix<N, N> {
+
+    pub fn identity() -> Matrix<N, N> {
+        let mut res = Matrix::zeros();
+        for i in 0..N {
+            res.0[i][i] = 1.0;
+        }
+        res
+    }
+
+    pub fn diagonal(values: [f32; N]) -> Matrix<N, N> {
+        let mut res = Matrix::zeros();
+        for (i, v) in values.iter().enumerate() {
+            res.0[i][i] = *v;
+        }
+        res
+    }
+
+    /**
+    General inverse by LU decomposition with partial pivoting.
+    A pivot is taken as zero below the rounding error relative to the largest entry of its row,
+    so that covariances of variables far apart in scale are still inverted.
+    */
+    pub fn inverse(&self) -> Result<Matrix<N, N>> {
+        let mut lu = self.0;
+        let mut inv = Matrix::<N, N>::identity().0;
+        let mut tolerances = self.0.map(|row| f32::EPSILON * N as f32 * row.iter().fold(0.0, |max, v| v.abs().max(max)));
+
+        for k in 0..N {
+            let pivot = (k..N)
+                .max_by(|a, b| lu[*a][k].abs().partial_cmp(&lu[*b][k].abs()).unwrap_or(std::cmp::Ordering::Equal))
+                .unwrap_or(k);
+            if lu[pivot][k].abs() <= tolerances[pivot] {return Err(Error::SingularCovariance);}
+
+            lu.swap(k, pivot);
+            inv.swap(k, pivot);
+            tolerances.swap(k, pivot);
+
+            for i in k + 1..N {
+                let factor = lu[i][k] / lu[k][k];
+                for j in 0..N {
+                    lu[i][j] -= factor * lu[k][j];
+                    inv[i][j] -= factor * inv[k][j];
+                }
+            }
+        }
+
+        for k in (0..N).rev() {
+            for i in k + 1..N {
+                let row = inv[i];
+                inv[k].iter_mut().zip(row.iter()).for_each(|(v, r)| *v -= lu[k][i] * r);
+            }
+            let pivot = lu[k][k];
+            inv[k].iter_mut().for_each(|v| *v /= pivot);
+        }
+
+        Ok(Matrix(inv))
+    }
+
+    /**
+    Lower triangular L with L Lᵀ equal to a symmetric positive definite matrix.
+    */
+    pub fn cholesky(&self) -> Result<Matrix<N, N>> {
+        let mut l = Matrix::<N, N>::zeros();
+
+        for i in 0..N {
+            for j in 0..=i {
+                let sum = (0..j).map(|k| l.0[i][k] * l.0[j][k]).sum::<f32>();
+                if i == j {
+                    let d = self.0[i][i] - sum;
+                    if d <= 0.0 {return Err(Error::SingularCovariance);}
+                    l.0[i][j] = d.sqrt();
+                } else {
+                    l.0[i][j] = (self.0[i][j] - sum) / l.0[j][j];
+                }
+            }
+        }
+
+        Ok(l)
+    }
+
+    /**
+    Inverse of a symmetric positive definite matrix through its Cholesky decomposition.
+    */
+    pub fn inverse_spd(&self) -> Result<Matrix<N, N>> {
+        let l_inv = self.cholesky()?.inverse()?;
+        Ok(l_inv.transpose() * l_inv)
+    }
+
+}
+
+impl<const R: usize, const C: usize> Add for Matrix<R, C> {
+    type Output = Matrix<R, C>;
+
+    fn add(self, other: Matrix<R, C>) -> Matrix<R, C> {
+        let mut res = self;
+        for i in 0..R {
+            for j in 0..C {
+                res.0[i][j] += other.0[i][j];
+            }
+        }
+        res
+    }
+}
+
+impl<const R: usize, const C: usize> Sub for Matrix<R, C> {
+    type Output = Matrix<R, C>;
+
+    fn sub(self, other: Matrix<R, C>) -> Matrix<R, C> {
+        let mut res = self;
+        for i in 0..R {
+            for j in 0..C {
+                res.0[i][j] -= other.0[i][j];
+            }
+        }
+        res
+    }
+}
+
+impl<const R: usize, const C: usize, const K: usize> Mul<Matrix<C, K>> for Matrix<R, C> {
+    type Output = Matrix<R, K>;
+
+    fn mul(self, other: Matrix<C, K>) -> Matrix<R, K> {
+        let mut res = Matrix::<R, K>::zeros();
+        for i in 0..R {
+            for j in 0..K {
+                res.0[i][j] = (0..C).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close<const R: usize, const C: usize>(a: &Matrix<R, C>, b: &Matrix<R, C>) {
+        for i in 0..R {
+            for j in 0..C {
+                assert!((a.0[i][j] - b.0[i][j]).abs() < 1e-4, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_and_transpose() {
+        let a = Matrix([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        let b = Matrix([[1.0], [0.0], [-1.0]]);
+
+        assert_eq!(a * b, Matrix([[-2.0], [-2.0]]));
+        assert_eq!(a.transpose(), Matrix([[1.0, 4.0], [2.0, 5.0], [3.0, 6.0]]));
+    }
+
+    #[test]
+    fn test_add_sub_scale() {
+        let a = Matrix([[1.0, 2.0], [3.0, 4.0]]);
+        let b = Matrix([[5.0, 6.0], [7.0, 8.0]]);
+
+        assert_eq!(a + b, Matrix([[6.0, 8.0], [10.0, 12.0]]));
+        assert_eq!(a - b, Matrix([[-4.0, -4.0], [-4.0, -4.0]]));
+        assert_eq!(a.scale(2.0), Matrix([[2.0, 4.0], [6.0, 8.0]]));
+    }
+
+    #[test]
+    fn test_inverse_3x3_with_pivoting() {
+        let a = Matrix([[0.0, 2.0, 1.0], [1.0, 1.0, 0.0], [3.0, 0.0, 1.0]]);
+
+        let inv = a.inverse().unwrap();
+
+        assert_close(&(a * inv), &Matrix::identity());
+    }
+
+    #[test]
+    fn test_inverse_should_fail_if_singular() {
+        let a = Matrix([[1.0, 1.0], [2.0, 2.0]]);
+        assert!(matches!(a.inverse(), Err(Error::SingularCovariance)));
+    }
+
+    #[test]
+    fn test_inverse_far_from_unit_scale() {
+        let small = Matrix([[2e-8, 1e-8], [1e-8, 3e-8]]);
+        assert_close(&(small * small.inverse().unwrap()), &Matrix::identity());
+
+        let mixed = Matrix([[1.3e-2, 6.7e-5, 0.0], [6.7e-5, 4.5e-7, 0.0], [0.0, 0.0, 1.0]]);
+        assert_close(&(mixed * mixed.inverse().unwrap()), &Matrix::identity());
+
+        let large = Matrix([[1e5 / 7.0, 1e5], [1e5, 7e5]]);
+        assert!(matches!(large.inverse(), Err(Error::SingularCovariance)));
+    }
+
+    #[test]
+    fn test_cholesky() {
+        let a = Matrix([[4.0, 2.0, 0.4], [2.0, 5.0, 1.0], [0.4, 1.0, 3.0]]);
+
+        let l = a.cholesky().unwrap();
+
+        assert_eq!(l.0[0][1], 0.0);
+        assert_close(&(l * l.transpose()), &a);
+        assert_close(&(a * a.inverse_spd().unwrap()), &Matrix::identity());
+    }
+
+    #[test]
+    fn test_cholesky_should_fail_if_not_positive_definite() {
+        let a = Matrix([[1.0, 2.0], [2.0, 1.0]]);
+        assert!(matches!(a.cholesky(), Err(Error::SingularCovariance)));
+    }
+}
@@ -0,0 +1,300 @@
+use image::GrayImage;
+use log::debug;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+
+use crate::error::{Error, Result};
+
+/**
+The largest raster image drawn on a page, converted to 8 bits grayscale.
+Returns None for a page without raster image, such as a typeset title page.
+*/
+pub fn page_image(doc: &Document, page: ObjectId) -> Result<Option<GrayImage>> {
+    let largest = image_streams(doc, page)
+        .into_iter()
+        .max_by_key(|s| integer(doc, &s.dict, b"Width").unwrap_or(0) * integer(doc, &s.dict, b"Height").unwrap_or(0));
+
+    match largest {
+        Some(stream) => decode_image(doc, stream).map(Some),
+        None => Ok(None)
+    }
+}
+
+/**
+Image XObjects of the page resources, inherited from the page tree when the page has none.
+*/
+fn image_streams(doc: &Document, page: ObjectId) -> Vec<&Stream> {
+    let (dict, ids) = doc.get_page_resources(page);
+    let resources = dict.or_else(|| ids.first().and_then(|id| doc.get_dictionary(*id).ok()));
+
+    let xobjects = resources
+        .and_then(|r| r.get(b"XObject").ok())
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| o.as_dict().ok());
+
+    xobjects
+        .into_iter()
+        .flat_map(|x| x.iter())
+        .filter_map(|(_, o)| doc.dereference(o).ok())
+        .filter_map(|(_, o)| o.as_stream().ok())
+        .filter(|s| s.dict.get(b"Subtype").and_then(Object::as_name_str).ok() == Some("Image"))
+        .collect()
+}
+
+fn dereference<'a>(doc: &'a Document, dict: &'a Dictionary, key: &[u8]) -> Option<&'a Object> {
+    dict.get(key).ok().and_then(|o| doc.dereference(o).ok()).map(|(_, o)| o)
+}
+
+fn integer(doc: &Document, dict: &Dictionary, key: &[u8]) -> Option<usize> {
+    dereference(doc, dict, key).and_then(|o| o.as_i64().ok()).map(|v| v.max(0) as usize)
+}
+
+fn boolean(doc: &Document, dict: &Dictionary, key: &[u8]) -> bool {
+    matches!(dereference(doc, dict, key), Some(Object::Boolean(true)))
+}
+
+/**
+Number of colour components of the image colour space, None when not supported.
+*/
+fn components(doc: &Document, dict: &Dictionary) -> Option<usize> {
+    let space = dereference(doc, dict, b"ColorSpace")?;
+    let (name, icc) = match space {
+        Object::Array(array) => (
+            array.first()?.as_name_str().ok()?,
+            array.get(1).and_then(|o| doc.dereference(o).ok()).and_then(|(_, o)| o.as_stream().ok())
+        ),
+        o => (o.as_name_str().ok()?, None)
+    };
+
+    match name {
+        "DeviceGray" | "CalGray" => Some(1),
+        "DeviceRGB" | "CalRGB" => Some(3),
+        "DeviceCMYK" => Some(4),
+        "ICCBased" => integer(doc, &icc?.dict, b"N"),
+        _ => None
+    }
+}
+
+fn decode_image(doc: &Document, stream: &Stream) -> Result<GrayImage> {
+    let dict = &stream.dict;
+    let width = integer(doc, dict, b"Width").unwrap_or(0);
+    let height = integer(doc, dict, b"Height").unwrap_or(0);
+    let mask = boolean(doc, dict, b"ImageMask");
+    // A Decode array starting with 1 maps the samples the other way round.
+    let inverted = dereference(doc, dict, b"Decode")
+        .and_then(|o| o.as_array().ok())
+        .and_then(|a| a.first())
+        .and_then(|o| o.as_f64().or_else(|_| o.as_i64().map(|v| v as f64)).ok())
+        .is_some_and(|v| v > 0.5);
+
+    let filters = stream.filters().unwrap_or_default();
+    let params = match dereference(doc, dict, b"DecodeParms") {
+        Some(Object::Array(array)) => array.last().and_then(|o| doc.dereference(o).ok()).and_then(|(_, o)| o.as_dict().ok()),
+        Some(o) => o.as_dict().ok(),
+        None => None
+    };
+
+    debug!("Decode PDF image {}x{} with filters:{:?}", width, height, filters);
+
+    let mut img = match filters.last().map(String::as_str) {
+        Some("DCTDecode") if filters.len() == 1 =>
+            image::load_from_memory_with_format(&stream.content, image::ImageFormat::Jpeg)?.into_luma8(),
+        Some("CCITTFaxDecode") if filters.len() == 1 => {
+            let param = |key: &[u8]| params.and_then(|p| dereference(doc, p, key));
+            let k = param(b"K").and_then(|o| o.as_i64().ok()).unwrap_or(0);
+            if k >= 0 {
+                return Err(Error::UnsupportedImage("CCITT Group 3 images".to_string()));
+            }
+            let columns = param(b"Columns").and_then(|o| o.as_i64().ok()).map_or(1728, |v| v.max(0) as usize);
+            let black_is_1 = params.is_some_and(|p| boolean(doc, p, b"BlackIs1"));
+
+            let mut img = crate::ccitt::decode_group4(&stream.content, columns, height)?;
+            // Black is the 0 sample unless BlackIs1, which the Decode array may map back to black.
+            if black_is_1 != inverted {
+                image::imageops::invert(&mut img);
+            }
+            return Ok(img);
+        },
+        Some("FlateDecode") | Some("LZWDecode") | None => {
+            let samples = match filters.is_empty() {
+                true => stream.content.clone(),
+                false => {
+                    // lopdf refuses to decompress image streams, their filters being left to the reader.
+                    let mut data = stream.clone();
+                    data.dict.remove(b"Subtype");
+                    data.decompressed_content()?
+                }
+            };
+            let (components, bits) = match mask {
+                true => (1, 1),
+                false => (
+                    components(doc, dict).ok_or_else(|| Error::UnsupportedImage("PDF colour space".to_string()))?,
+                    integer(doc, dict, b"BitsPerComponent").unwrap_or(8)
+                )
+            };
+            samples_to_luma(&samples, width, height, components, bits)?
+        },
+        Some(filter) => return Err(Error::UnsupportedImage(format!("PDF images with the {} filter", filter)))
+    };
+
+    if inverted {
+        image::imageops::invert(&mut img);
+    }
+    Ok(img)
+}
+
+/**
+Converts raw image samples, rows padded to a whole byte, to 8 bits grayscale.
+*/
+fn samples_to_luma(samples: &[u8], width: usize, height: usize, components: usize, bits: usize) -> Result<GrayImage> {
+    let row_len = (width * components * bits).div_ceil(8);
+    let supported = match bits {
+        1 | 2 | 4 => components == 1,
+        8 | 16 => [1, 3, 4].contains(&components),
+        _ => false
+    };
+    if !supported {
+        return Err(Error::UnsupportedImage(format!("{} components of {} bits", components, bits)));
+    }
+    if samples.len() < row_len * height {
+        return Err(Error::UnsupportedImage(format!("{} bytes for a {}x{} image", samples.len(), width, height)));
+    }
+
+    let sample = |row: &[u8], i: usize| -> u32 {
+        match bits {
+            8 => row[i] as u32,
+            16 => row[2 * i] as u32,
+            _ => {
+                let max = (1 << bits) - 1;
+                let bit = i * bits;
+                let v = (row[bit / 8] >> (8 - bits - bit % 8)) as u32 & max;
+                v * 255 / max
+            }
+        }
+    };
+
+    Ok(GrayImage::from_fn(width as u32, height as u32, |x, y| {
+        let row = &samples[y as usize * row_len..(y as usize + 1) * row_len];
+        let i = x as usize * components;
+        let luma = match components {
+            1 => sample(row, i),
+            3 => (2126 * sample(row, i) + 7152 * sample(row, i + 1) + 722 * sample(row, i + 2)) / 10000,
+            _ => {
+                let rgb = |c: u32| 255 - (c + sample(row, i + 3)).min(255);
+                (2126 * rgb(sample(row, i)) + 7152 * rgb(sample(row, i + 1)) + 722 * rgb(sample(row, i + 2))) / 10000
+            }
+        };
+        image::Luma([luma as u8])
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use lopdf::dictionary;
+
+    /**
+    A PDF with one page per image stream, the image drawn over the whole page.
+    */
+    pub(crate) fn pdf_with_images(images: Vec<Stream>) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let mut kids = Vec::<Object>::new();
+
+        for stream in images {
+            let image_id = doc.add_object(stream);
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+                "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } }
+            });
+            kids.push(page_id.into());
+        }
+
+        let count = kids.len() as i64;
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }));
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut buffer = Vec::new();
+        doc.save_to(&mut buffer).unwrap();
+        buffer
+    }
+
+    pub(crate) fn gray_stream(width: i64, height: i64, samples: Vec<u8>) -> Stream {
+        Stream::new(dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8
+        }, samples)
+    }
+
+    fn first_page_image(pdf: &[u8]) -> Result<Option<GrayImage>> {
+        let doc = Document::load_mem(pdf)?;
+        let page = *doc.get_pages().values().next().unwrap();
+        page_image(&doc, page)
+    }
+
+    #[test]
+    fn test_flate_gray_image() {
+        let samples = [vec![0; 64], vec![255; 64]].concat();
+        let mut stream = gray_stream(32, 4, samples.clone());
+        stream.compress().unwrap();
+        assert_eq!(stream.filters().unwrap(), vec!["FlateDecode".to_string()]);
+
+        let img = first_page_image(&pdf_with_images(vec![stream])).unwrap().unwrap();
+
+        assert_eq!(img.dimensions(), (32, 4));
+        assert_eq!(img.into_raw(), samples);
+    }
+
+    #[test]
+    fn test_bilevel_mask_and_rgb_samples() {
+        assert_eq!(samples_to_luma(&[0b1010_0000], 3, 1, 1, 1).unwrap().into_raw(), vec![255, 0, 255]);
+        assert_eq!(samples_to_luma(&[255, 255, 255, 0, 0, 0], 2, 1, 3, 8).unwrap().into_raw(), vec![255, 0]);
+        assert_eq!(samples_to_luma(&[0, 0, 0, 255], 1, 1, 4, 8).unwrap().into_raw(), vec![0]);
+        assert!(matches!(samples_to_luma(&[0], 2, 2, 1, 8), Err(Error::UnsupportedImage(_))));
+    }
+
+    #[test]
+    fn test_ccitt_image_with_decode_array() {
+        // One blank row then one black pixel at column 1 of a 4 pixels wide image.
+        let data = vec![0b1001_0001, 0b1101_0100];
+        let stream = Stream::new(dictionary! {
+            "Subtype" => "Image",
+            "Width" => 4,
+            "Height" => 2,
+            "ImageMask" => true,
+            "Decode" => vec![1.into(), 0.into()],
+            "Filter" => "CCITTFaxDecode",
+            "DecodeParms" => dictionary! { "K" => -1, "Columns" => 4 }
+        }, data);
+
+        let img = first_page_image(&pdf_with_images(vec![stream])).unwrap().unwrap();
+
+        assert_eq!(img.into_raw(), vec![0, 0, 0, 0, 0, 255, 0, 0]);
+    }
+
+    #[test]
+    fn test_unsupported_filter() {
+        let mut stream = gray_stream(1, 1, vec![0]);
+        stream.dict.set("Filter", "JBIG2Decode");
+
+        let res = first_page_image(&pdf_with_images(vec![stream]));
+
+        assert!(matches!(res, Err(Error::UnsupportedImage(_))));
+    }
+
+    #[test]
+    fn test_page_without_image() {
+        let pdf = pdf_with_images(vec![]);
+        let mut doc = Document::load_mem(&pdf).unwrap();
+        let page = doc.add_object(dictionary! { "Type" => "Page" });
+
+        assert!(page_image(&doc, page).unwrap().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use log::debug;
+
+use crate::staves::Staff;
+
+// Extra height in pixel allowed over the line thickness before a run is kept as a symbol.
+const THICKNESS_TOLERANCE: usize = 1;
+
+/**
+image : The column-major buffer without the staff lines.
+mask : The removed pixels as ink (0) on a white (255) background.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub image: Vec<u8>,
+    pub mask: Vec<u8>
+}
+
+/**
+buffer_vertical : The binarized column-major buffer given to `staves::detect_staves`.
+height : The column length.
+staves : The tracked lines.
+line_thickness : The estimated staff line height, see `analysis::PageMetrics`.
+
+For each column of a staff, every vertical run holding its pixels is only erased when
+it is not taller than the line, so that symbols lying on the line are preserved.
+White pixels between the runs are left out of the mask.
+*/
+pub fn remove_staves(buffer_vertical: &[u8], height: usize, staves: &[Staff], line_thickness: usize) -> Removal {
+    let mut image = buffer_vertical.to_vec();
+    let mut mask = vec![255; buffer_vertical.len()];
+    let max_height = line_thickness + THICKNESS_TOLERANCE;
+
+    for staff in staves {
+        for (xs, y) in &staff.buffer {
+            let column = (y - 1) * height;
+            let mut last_bottom = None;
+
+            for x in xs {
+                if last_bottom.is_some_and(|bottom| x - 1 <= bottom) {continue;}
+                let (top, bottom) = run_bounds(&buffer_vertical[column..column + height], x - 1, x - 1);
+                last_bottom = Some(bottom);
+
+                if bottom - top + 1 > max_height {
+                    debug!("Keep run {:?}-{:?} on column:{:?}", top, bottom, y);
+                    continue;
+                }
+
+                for r in top..=bottom {
+                    image[column + r] = 255;
+                    mask[column + r] = 0;
+                }
+            }
+        }
+    }
+
+    Removal { image, mask }
+}
+
+/**
+Extends the rows `top` to `bottom` to the whole black run holding them.
+*/
+fn run_bounds(column: &[u8], top: usize, bottom: usize) -> (usize, usize) {
+    let mut top = top;
+    let mut bottom = bottom;
+    while top > 0 && column[top - 1] == 0 {top -= 1;}
+    while bottom + 1 < column.len() && column[bottom + 1] == 0 {bottom += 1;}
+    (top, bottom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::staves::detect_staves;
+
+    #[test]
+    fn test_run_bounds() {
+        let column = vec![255, 0, 0, 0, 255, 0];
+        assert_eq!(run_bounds(&column, 2, 2), (1, 3));
+        assert_eq!(run_bounds(&column, 5, 5), (5, 5));
+    }
+
+    #[test]
+    fn test_remove_line_and_keep_note_head() {
+        let height = 20;
+        let width = 30;
+        let mut buffer = vec![255; width * height];
+        for c in 0..width {
+            buffer[c * height + 10] = 0;
+        }
+        for c in 12..16 {
+            for r in 8..13 {
+                buffer[c * height + r] = 0;
+            }
+        }
+        let staves = detect_staves(buffer.clone(), height).unwrap();
+
+        let removal = remove_staves(&buffer, height, &staves, 1);
+
+        for c in 0..width {
+            let expected = if (12..16).contains(&c) {0} else {255};
+            assert_eq!(removal.image[c * height + 10], expected);
+            assert_eq!(removal.mask[c * height + 10], 255 - expected);
+        }
+        assert_eq!(removal.image.iter().filter(|v| **v == 0).count(), 4 * 5);
+        assert_eq!(removal.mask.iter().filter(|v| **v == 0).count(), width - 4);
+    }
+
+    #[test]
+    fn test_mask_only_run_pixels() {
+        let height = 10;
+        let width = 20;
+        let mut buffer = vec![255; width * height];
+        for c in 0..width {
+            buffer[c * height + 4] = 0;
+        }
+        let mut staves = detect_staves(buffer.clone(), height).unwrap();
+        // A line two pixels thick split by a white row on every column.
+        for c in 0..width {
+            buffer[c * height + 6] = 0;
+        }
+        for (xs, _) in staves[0].buffer.iter_mut() {
+            *xs = vec![5, 7];
+        }
+
+        let removal = remove_staves(&buffer, height, &staves, 1);
+
+        assert!(removal.image.iter().all(|v| *v == 255));
+        for c in 0..width {
+            assert_eq!(&removal.mask[c * height + 3..c * height + 8], &[255, 0, 255, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn test_remove_nothing_without_staves() {
+        let buffer = vec![0, 255, 0, 255];
+        let removal = remove_staves(&buffer, 2, &[], 1);
+
+        assert_eq!(removal.image, buffer);
+        assert_eq!(removal.mask, vec![255; 4]);
+    }
+}
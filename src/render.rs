@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use image::{GrayImage, Rgb, RgbImage};
+
+use crate::error::Result;
+use crate::staves::Staff;
+
+// Size in pixel of the start and end markers.
+const MARKER_SIZE: i64 = 2;
+
+/**
+Draws each staff over a grayscale page in its own colour: the centreline predicted
+by the Kalman filter in a darker shade, the accepted pixel runs on top of it,
+a square at the first column and a cross at the last one.
+*/
+pub fn overlay(img: &GrayImage, staves: &[Staff]) -> RgbImage {
+    let mut res = RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let v = img.get_pixel(x, y).0[0];
+        Rgb([v, v, v])
+    });
+
+    for (i, staff) in staves.iter().enumerate() {
+        let color = track_color(i);
+        let shade = Rgb([color.0[0] / 2, color.0[1] / 2, color.0[2] / 2]);
+
+        for w in staff.predictions().windows(2) {
+            draw_segment(&mut res, w[0], w[1], shade);
+        }
+        if let [(y, x)] = staff.predictions() {
+            put_pixel(&mut res, *y as i64, x.floor() as i64, shade);
+        }
+
+        for (xs, y) in &staff.buffer {
+            for x in xs {
+                put_pixel(&mut res, *y as i64, *x as i64, color);
+            }
+        }
+
+        let centres = staff.centres();
+        if let (Some(first), Some(last)) = (centres.first(), centres.last()) {
+            draw_square(&mut res, first.0 as i64, first.1.floor() as i64, color);
+            draw_cross(&mut res, last.0 as i64, last.1.floor() as i64, color);
+        }
+    }
+
+    res
+}
+
+pub fn save_overlay<P: AsRef<Path>>(img: &GrayImage, staves: &[Staff], path: P) -> Result<()> {
+    overlay(img, staves).save(path)?;
+    Ok(())
+}
+
+/**
+Spreads hues with the golden angle so that neighbouring tracks get distinct colours.
+*/
+pub fn track_color(i: usize) -> Rgb<u8> {
+    let hue = (i as f32 * 137.508) % 360.0;
+    let sector = hue / 60.0;
+    let c = 230.0;
+    let x = c * (1.0 - (sector % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match sector as usize {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x)
+    };
+
+    Rgb([r as u8, g as u8, b as u8])
+}
+
+/**
+Joins two predicted centres `(column, x)`, one pixel per column.
+*/
+fn draw_segment(img: &mut RgbImage, from: (usize, f32), to: (usize, f32), color: Rgb<u8>) {
+    let steps = to.0.saturating_sub(from.0).max(1);
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = from.1 + t * (to.1 - from.1);
+        put_pixel(img, (from.0 + step) as i64, x.floor() as i64, color);
+    }
+}
+
+fn draw_square(img: &mut RgbImage, column: i64, row: i64, color: Rgb<u8>) {
+    for d in -MARKER_SIZE..=MARKER_SIZE {
+        put_pixel(img, column + d, row - MARKER_SIZE, color);
+        put_pixel(img, column + d, row + MARKER_SIZE, color);
+        put_pixel(img, column - MARKER_SIZE, row + d, color);
+        put_pixel(img, column + MARKER_SIZE, row + d, color);
+    }
+}
+
+fn draw_cross(img: &mut RgbImage, column: i64, row: i64, color: Rgb<u8>) {
+    for d in -MARKER_SIZE..=MARKER_SIZE {
+        put_pixel(img, column + d, row + d, color);
+        put_pixel(img, column + d, row - d, color);
+    }
+}
+
+/**
+Puts a pixel at the 1-based `column` and `row` used by `Staff::buffer`, ignoring positions outside the image.
+*/
+fn put_pixel(img: &mut RgbImage, column: i64, row: i64, color: Rgb<u8>) {
+    if column < 1 || row < 1 || column > img.width() as i64 || row > img.height() as i64 {return;}
+    img.put_pixel(column as u32 - 1, row as u32 - 1, color);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::staves::detect_staves;
+
+    #[test]
+    fn test_track_colors_are_distinct() {
+        let colors = (0..8).map(track_color).collect::<Vec<Rgb<u8>>>();
+        for (i, a) in colors.iter().enumerate() {
+            for b in &colors[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlay_draw_runs_and_markers() {
+        let img = GrayImage::from_fn(20, 12, |_, y| image::Luma([if y == 5 {0} else {255}]));
+        let staves = detect_staves(crate::to_column_major(&img), 12).unwrap();
+
+        let res = overlay(&img, &staves);
+
+        let color = track_color(0);
+        for x in 3..17 {
+            assert_eq!(*res.get_pixel(x, 5), color);
+            assert_eq!(*res.get_pixel(x, 9), Rgb([255, 255, 255]));
+        }
+        // Square around the first column, cross over the last one.
+        assert_eq!(*res.get_pixel(2, 3), color);
+        assert_eq!(*res.get_pixel(0, 7), color);
+        assert_eq!(*res.get_pixel(17, 3), color);
+        assert_eq!(*res.get_pixel(18, 3), Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn test_draw_segment_fill_gaps() {
+        let mut img = RgbImage::new(10, 10);
+        let color = Rgb([1, 2, 3]);
+
+        draw_segment(&mut img, (2, 2.5), (6, 6.5), color);
+
+        for c in 2..=6 {
+            assert_eq!(*img.get_pixel(c - 1, c - 1), color);
+        }
+    }
+}
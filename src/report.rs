@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+
+use crate::barlines::Barline;
+use crate::error::{Error, Result};
+use crate::scanner::ScanResult;
+use crate::staves::Staff;
+use crate::systems::StaffGroup;
+
+/**
+Version of the JSON layout, bumped on every change of the meaning or the shape of `Report`.
+
+1 : Tracks on the page as scanned.
+2 : Tracks on the deskewed page, with the page index, the bridged gaps, the smoothed centres and the barlines.
+3 : Track state and covariance over position, speed and acceleration.
+*/
+pub const SCHEMA_VERSION: u32 = 3;
+
+/**
+Machine-readable detection result.
+
+schema_version : The `SCHEMA_VERSION` the report was written with.
+page : The 1-based page index in the scanned document.
+width, height : The page dimensions in pixel.
+skew : The page angle in degree, tracks are given on the deskewed page.
+tracks : Every tracked line, `id` being its index.
+groups : The staves, referencing tracks by `id`.
+barlines : The barlines, referencing staves by their index in `groups`.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub schema_version: u32,
+    #[serde(default = "first_page")]
+    pub page: usize,
+    pub width: usize,
+    pub height: usize,
+    #[serde(default)]
+    pub skew: f32,
+    pub tracks: Vec<TrackReport>,
+    pub groups: Vec<StaffGroup>,
+    #[serde(default)]
+    pub barlines: Vec<Barline>
+}
+
+fn first_page() -> usize {
+    1
+}
+
+/**
+start, end : First and last 1-based column of the track.
+columns : The matched pixels of each column.
+gaps : Column ranges, bounds included, bridged by merging fragments of the line.
+smoothed : The smoothed line centre of every column from start to end, as `[column, centre]`.
+state : Final Kalman state estimate, position, speed and acceleration.
+covariance : Final Kalman state covariance, one row per state variable.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackReport {
+    pub id: usize,
+    pub start: usize,
+    pub end: usize,
+    pub columns: Vec<ColumnReport>,
+    #[serde(default)]
+    pub gaps: Vec<(usize, usize)>,
+    #[serde(default)]
+    pub smoothed: Vec<(usize, f32)>,
+    pub state: Vec<f32>,
+    pub covariance: Vec<Vec<f32>>
+}
+
+/**
+column : The 1-based column.
+centre : The pixel centre of the line, 1-based rows.
+thickness : The number of matched pixels.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnReport {
+    pub column: usize,
+    pub centre: f32,
+    pub thickness: usize
+}
+
+impl TrackReport {
+
+    pub fn new(id: usize, staff: &Staff) -> TrackReport {
+        let x = staff.state();
+        let p = staff.covariance();
+
+        TrackReport {
+            id,
+            start: staff.start(),
+            end: staff.end(),
+            columns: staff.centres()
+                .into_iter()
+                .zip(staff.buffer.iter())
+                .map(|((column, centre), (xs, _))| ColumnReport { column, centre, thickness: xs.len() })
+                .collect(),
+            gaps: staff.gaps().to_vec(),
+            smoothed: staff.smoothed().to_vec(),
+            state: x.0.iter().map(|row| row[0]).collect(),
+            covariance: p.0.iter().map(|row| row.to_vec()).collect()
+        }
+    }
+
+}
+
+impl Report {
+
+    pub fn new(result: &ScanResult) -> Report {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            page: result.page,
+            width: result.width,
+            height: result.height,
+            skew: result.skew,
+            tracks: result.staves
+                .iter()
+                .enumerate()
+                .map(|(id, staff)| TrackReport::new(id, staff))
+                .collect(),
+            groups: result.groups.clone(),
+            barlines: result.barlines.clone()
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /**
+    Reads a report, failing on a report written with a schema newer than `SCHEMA_VERSION`.
+    */
+    pub fn from_json(json: &str) -> Result<Report> {
+        let report: Report = serde_json::from_str(json)?;
+        if report.schema_version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchema { version: report.schema_version, supported: SCHEMA_VERSION });
+        }
+        Ok(report)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Scanner;
+
+    #[test]
+    fn test_report_round_trip() {
+        let result = Scanner::default().scan_path("score_sample/score_sample1.png").unwrap();
+        let report = Report::new(&result);
+
+        let json = report.to_json().unwrap();
+
+        assert_eq!(Report::from_json(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn test_report_tracks() {
+        let result = Scanner::default().scan_path("score_sample/single_line_top.png").unwrap();
+        let report = Report::new(&result);
+
+        assert_eq!(report.schema_version, SCHEMA_VERSION);
+        assert_eq!(report.tracks.len(), 1);
+
+        let track = &report.tracks[0];
+        assert_eq!((track.id, track.start, track.end), (0, 1, 10));
+        assert_eq!(track.columns.len(), 10);
+        assert_eq!(track.columns[0], ColumnReport { column: 1, centre: 1.5, thickness: 1 });
+        assert_eq!(track.smoothed.len(), 10);
+        assert!(track.gaps.is_empty());
+        assert!((track.smoothed[0].1 - 1.5).abs() < 1e-3);
+        assert_eq!(track.state[0], result.staves[0].state().0[0][0]);
+        assert_eq!(track.covariance.len(), track.state.len());
+        assert!(track.covariance.iter().all(|row| row.len() == track.state.len()));
+    }
+
+    #[test]
+    fn test_report_json_layout() {
+        let result = Scanner::default().scan_path("score_sample/score_sample1.png").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&Report::new(&result).to_json().unwrap()).unwrap();
+
+        assert_eq!(json["schema_version"], 3);
+        assert_eq!(json["page"], 1);
+        assert_eq!(json["groups"][0]["kind"], "staff");
+        assert_eq!(json["groups"][0]["lines"].as_array().unwrap().len(), 5);
+        assert_eq!(json["tracks"][0]["state"].as_array().unwrap().len(), 3);
+        assert_eq!(json["tracks"][0]["covariance"].as_array().unwrap().len(), 3);
+        assert_eq!(json["tracks"][0]["covariance"][2].as_array().unwrap().len(), 3);
+        assert_eq!(json["barlines"][0]["kind"], "single");
+        assert_eq!(json["barlines"][0]["last_staff"], 1);
+    }
+
+    #[test]
+    fn test_report_without_page_is_first_page() {
+        let result = Scanner::default().scan_path("score_sample/single_line_top.png").unwrap();
+        let mut json: serde_json::Value = serde_json::from_str(&Report::new(&result).to_json().unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("page");
+
+        assert_eq!(Report::from_json(&json.to_string()).unwrap().page, 1);
+    }
+
+    #[test]
+    fn test_reject_invalid_json() {
+        assert!(matches!(Report::from_json("{}"), Err(crate::Error::Json(_))));
+    }
+
+    #[test]
+    fn test_reject_newer_schema() {
+        let result = Scanner::default().scan_path("score_sample/single_line_top.png").unwrap();
+        let mut report = Report::new(&result);
+
+        report.schema_version = SCHEMA_VERSION - 1;
+        assert!(Report::from_json(&report.to_json().unwrap()).is_ok());
+
+        report.schema_version = SCHEMA_VERSION + 1;
+        assert!(matches!(
+            Report::from_json(&report.to_json().unwrap()),
+            Err(Error::UnsupportedSchema { version, supported }) if version == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+use std::path::Path;
+
+use image::{DynamicImage, GrayImage};
+use log::debug;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::analysis::PageMetrics;
+use crate::barlines::Barline;
+use crate::binarize::Method;
+use crate::error::Result;
+use crate::staves::{Staff, Strips, TrackerConfig};
+use crate::systems::StaffGroup;
+
+/**
+binarization : The thresholding method applied to the grayscale page.
+tracker : Fixed tracker parameters, derived from the page metrics when None.
+deskew : Straighten the page before tracking lines.
+strips : Track wide pages in overlapping vertical strips, see `staves::detect_staves_in_strips`.
+The strips are tracked concurrently with the `parallel` feature.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanConfig {
+    pub binarization: Method,
+    pub tracker: Option<TrackerConfig>,
+    pub deskew: bool,
+    pub strips: Option<Strips>
+}
+
+impl Default for ScanConfig {
+    fn default() -> ScanConfig {
+        ScanConfig {
+            binarization: Method::default(),
+            tracker: None,
+            deskew: true,
+            strips: None
+        }
+    }
+}
+
+/**
+page : The 1-based page index in the scanned document, 1 for a single image.
+width, height : The page dimensions in pixel.
+skew : The page angle in degree measured by `deskew::estimate_skew`, 0 when not deskewed.
+buffer : The binarized and deskewed page as a column-major buffer, see `to_column_major`.
+metrics : The page metrics, None for a page without staff-like runs.
+staves : Every tracked line.
+groups : The tracked lines grouped into staves.
+barlines : The barlines of the staves, see `barlines::detect_barlines`.
+*/
+#[derive(Debug)]
+pub struct ScanResult {
+    pub page: usize,
+    pub width: usize,
+    pub height: usize,
+    pub skew: f32,
+    pub buffer: Vec<u8>,
+    pub metrics: Option<PageMetrics>,
+    pub staves: Vec<Staff>,
+    pub groups: Vec<StaffGroup>,
+    pub barlines: Vec<Barline>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scanner {
+    config: ScanConfig
+}
+
+impl Scanner {
+
+    pub fn new(config: ScanConfig) -> Scanner {
+        Scanner { config }
+    }
+
+    pub fn config(&self) -> &ScanConfig {
+        &self.config
+    }
+
+    pub fn scan_path<P: AsRef<Path>>(&self, path: P) -> Result<ScanResult> {
+        let img = crate::input::open_luma(path)?;
+        self.scan_image(&DynamicImage::ImageLuma8(img))
+    }
+
+    /**
+    Scans every page of a multi-page TIFF, of a PDF of scanned images or of a single image, see `input::open_pages`.
+    Returns the 1-based index and the result of every page in page order, an unreadable page failing alone.
+    Pages are scanned as the iterator is consumed, concurrently by batches of the number of threads
+    with the `parallel` feature, so that a long document is never held in memory at once.
+    */
+    pub fn scan_document<P: AsRef<Path>>(&self, path: P) -> Result<impl Iterator<Item = (usize, Result<ScanResult>)> + '_> {
+        let mut pages = crate::input::open_pages(path)?;
+        let scan = move |(index, page): (usize, Result<(usize, GrayImage)>)| {
+            let result = page.and_then(|(_, img)| {
+                debug!("Scan page {}", index);
+                self.scan_image(&DynamicImage::ImageLuma8(img))
+            });
+            (index, result.map(|result| ScanResult { page: index, ..result }))
+        };
+
+        #[cfg(feature = "parallel")]
+        let batch = rayon::current_num_threads().max(1);
+        #[cfg(not(feature = "parallel"))]
+        let batch = 1;
+
+        let mut scanned = Vec::new().into_iter();
+        Ok(std::iter::from_fn(move || {
+            if scanned.len() == 0 {
+                let pages = std::iter::from_fn(|| pages.next().map(|page| (pages.index(), page)))
+                    .take(batch)
+                    .collect::<Vec<_>>();
+
+                #[cfg(feature = "parallel")]
+                let results = pages.into_par_iter().map(scan).collect::<Vec<_>>();
+                #[cfg(not(feature = "parallel"))]
+                let results = pages.into_iter().map(scan).collect::<Vec<_>>();
+
+                scanned = results.into_iter();
+            }
+            scanned.next()
+        }))
+    }
+
+    pub fn scan_image(&self, img: &DynamicImage) -> Result<ScanResult> {
+        let img = img.to_luma8();
+        let width = img.width() as usize;
+        let height = img.height() as usize;
+
+        let binarize = |img: &image::GrayImage| crate::binarize::binarize(
+            &crate::to_column_major(img),
+            height,
+            &self.config.binarization
+        );
+
+        let mut buffer = binarize(&img);
+        let mut skew = 0.0;
+
+        if self.config.deskew {
+            let angle = crate::deskew::estimate_skew(&buffer, height);
+            if angle.abs() >= crate::deskew::MIN_SKEW {
+                buffer = binarize(&crate::deskew::rotate(&img, angle));
+                skew = angle;
+            }
+        }
+
+        let metrics = crate::analysis::analyse(&buffer, height);
+        let tracker = self.config.tracker
+            .or_else(|| metrics.map(|m| m.tracker_config()))
+            .unwrap_or_default();
+
+        debug!("Scan page {:?}x{:?} with tracker:{:?}", width, height, tracker);
+
+        let staves = match &self.config.strips {
+            Some(strips) => crate::staves::detect_staves_in_strips(buffer.clone(), height, &tracker, strips)?,
+            None => crate::staves::detect_staves_with(buffer.clone(), height, &tracker)?
+        };
+        let groups = crate::systems::group_staves(&staves);
+        let barlines = crate::barlines::detect_barlines(&buffer, height, &staves, &groups);
+
+        Ok(ScanResult { page: 1, width, height, skew, buffer, metrics, staves, groups, barlines })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::Error;
+
+    #[test]
+    fn test_scan_path() {
+        let result = Scanner::default().scan_path("score_sample/score_sample1.png").unwrap();
+
+        assert_eq!((result.width, result.height), (338, 149));
+        assert_eq!(result.buffer.len(), 338 * 149);
+        assert_eq!(result.groups.len(), 2);
+    }
+
+    #[test]
+    fn test_scan_document_pages() {
+        let path = std::env::temp_dir().join(format!("rustscanscore_{}_score.pdf", std::process::id()));
+        let page = crate::input::open_luma("score_sample/score_sample1.png").unwrap();
+        let blank = vec![255; 10 * 10];
+        std::fs::write(&path, crate::pdf::tests::pdf_with_images(vec![
+            crate::pdf::tests::gray_stream(10, 10, blank),
+            crate::pdf::tests::gray_stream(10, 10, vec![255; 3]),
+            crate::pdf::tests::gray_stream(page.width() as i64, page.height() as i64, page.into_raw())
+        ])).unwrap();
+
+        let results = Scanner::default().scan_document(&path).unwrap().collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+
+        // The truncated second page fails alone.
+        assert_eq!(results.iter().map(|(i, _)| *i).collect::<Vec<usize>>(), vec![1, 2, 3]);
+        assert!(results[0].1.as_ref().unwrap().staves.is_empty());
+        assert!(results[1].1.is_err());
+        let last = results[2].1.as_ref().unwrap();
+        assert_eq!((last.page, last.groups.len()), (3, 2));
+    }
+
+    #[test]
+    fn test_scan_in_strips() {
+        let strips = Some(Strips { width: 100, overlap: 40 });
+        let whole = Scanner::default()
+            .scan_path("score_sample/score_sample1.png")
+            .unwrap();
+        let result = Scanner::new(ScanConfig { strips, ..ScanConfig::default() })
+            .scan_path("score_sample/score_sample1.png")
+            .unwrap();
+
+        // The parallel feature only changes how strips are tracked, not whether they are used.
+        assert_eq!(ScanConfig::default().strips, None);
+        assert_eq!(result.groups.len(), 2);
+        assert_eq!(result.staves.len(), whole.staves.len());
+        for (a, b) in result.staves.iter().zip(whole.staves.iter()) {
+            assert_eq!((a.start(), a.end()), (b.start(), b.end()));
+            assert!((a.position() - b.position()).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_scan_with_fixed_tracker() {
+        let config = ScanConfig { tracker: Some(TrackerConfig::default()), ..ScanConfig::default() };
+        let result = Scanner::new(config).scan_path("score_sample/single_line_top.png").unwrap();
+
+        assert_eq!(result.staves.len(), 1);
+        assert_eq!(result.metrics, None);
+    }
+
+    #[test]
+    fn test_scan_skewed_page() {
+        let img = crate::input::open_luma("score_sample/score_sample1.png").unwrap();
+        let skewed = DynamicImage::ImageLuma8(crate::deskew::rotate(&img, -4.0));
+
+        let result = Scanner::default().scan_image(&skewed).unwrap();
+        assert!((result.skew - 4.0).abs() < 0.1);
+        assert_eq!(result.groups.len(), 2);
+
+        let config = ScanConfig { deskew: false, ..ScanConfig::default() };
+        let result = Scanner::new(config).scan_image(&skewed).unwrap();
+        assert_eq!(result.skew, 0.0);
+        let longest = result.staves.iter().max_by_key(|s| s.buffer.len()).unwrap();
+        let centres = longest.centres();
+        let (first, last) = (centres[0], centres[centres.len() - 1]);
+        let slope = (last.1 - first.1) / (last.0 - first.0) as f32;
+        assert!((slope - 4.0f32.to_radians().tan()).abs() < 0.02, "{}", slope);
+    }
+
+    #[test]
+    fn test_scan_empty_image_fails() {
+        let img = DynamicImage::new_luma8(0, 0);
+        assert!(matches!(Scanner::default().scan_image(&img), Err(Error::InvalidDimensions { .. })));
+    }
+}
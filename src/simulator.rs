@@ -1,100 +1,237 @@
-use image::{ImageBuffer, Rgb, ImageError};
+use std::path::Path;
+
+use image::{ImageBuffer, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::kalman::KalmanFilter;
+use crate::matrix::Matrix;
+
+
+/**
+A run of the Kalman filter over a simulated noisy line, one value per step.
+
+truth : The line without noise.
+measurements : The noisy line given to the filter.
+predictions : The filter estimate before each measurement, the first measurement for the first step.
+estimates : The filter estimate after each measurement.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub truth: Vec<f32>,
+    pub measurements: Vec<f32>,
+    pub predictions: Vec<f32>,
+    pub estimates: Vec<f32>
+}
+
+/**
+Statistics of the differences between two series.
+
+mean : Mean of the differences.
+std_dev : Standard deviation of the differences.
+rms : Root mean square of the differences.
+max : Largest absolute difference.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Residuals {
+    pub mean: f32,
+    pub std_dev: f32,
+    pub rms: f32,
+    pub max: f32
+}
+
+impl Residuals {
+
+    pub fn between(a: &[f32], b: &[f32]) -> Residuals {
+        let diffs = a.iter().zip(b).map(|(a, b)| a - b).collect::<Vec<f32>>();
+        if diffs.is_empty() {
+            return Residuals::default();
+        }
+        let n = diffs.len() as f32;
+        let mean = diffs.iter().sum::<f32>() / n;
+        let square = diffs.iter().map(|d| d * d).sum::<f32>() / n;
+
+        Residuals {
+            mean,
+            std_dev: (square - mean * mean).max(0.0).sqrt(),
+            rms: square.sqrt(),
+            max: diffs.iter().fold(0.0, |m, d| m.max(d.abs()))
+        }
+    }
+
+}
+
+impl Simulation {
 
+    /**
+    Innovations of the filter, the measurements minus the predictions.
+    */
+    pub fn innovations(&self) -> Residuals {
+        Residuals::between(&self.measurements, &self.predictions)
+    }
+
+    /**
+    Errors of the filter, the estimates minus the truth.
+    */
+    pub fn errors(&self) -> Residuals {
+        Residuals::between(&self.estimates, &self.truth)
+    }
+
+    /**
+    Errors of the raw measurements, the measurements minus the truth.
+    */
+    pub fn noise(&self) -> Residuals {
+        Residuals::between(&self.measurements, &self.truth)
+    }
+
+    /**
+    Measurements (red) and estimates (blue) over a 512x512 white image, one column per step.
+    */
+    pub fn draw(&self) -> RgbImage {
+        let mut img = ImageBuffer::from_fn(512, 512, |_x, _y| {
+            image::Rgb([255, 255, 255])
+        });
+
+        let mut put = |x: usize, y: f32, colour: Rgb<u8>| {
+            if x < 512 && (0.0..512.0).contains(&y) {
+                img.put_pixel(x as u32, y as u32, colour);
+            }
+        };
+        for (i, (measure, estimate)) in self.measurements.iter().zip(&self.estimates).enumerate() {
+            put(i + 1, *measure, Rgb::<u8>([255, 0, 0]));
+            put(i + 1, *estimate, Rgb::<u8>([0, 0, 255]));
+        }
 
-pub fn line() -> Result<(), ImageError> {
+        img
+    }
 
-    let mut img = ImageBuffer::from_fn(512, 512, |_x, _y| {
-        image::Rgb([255, 255, 255])
-    });
+}
 
-    
+/**
+Runs the Kalman filter over a line drawn by `sample_line_gen`, measuring the position and its variation.
+*/
+pub fn simulate<R: Rng>(rng: &mut R) -> crate::error::Result<Simulation> {
+    let measurements = sample_line_gen(rng);
+    let truth = true_line(measurements.len());
 
-    let measurements = sample_line_gen();
-    let mut measurement_iter = measurements.iter();
+    let mut last_measure = measurements[0];
 
-    let mut last_measure= *measurement_iter.next().unwrap(); 
+    let mut filter = KalmanFilter::constant_velocity(last_measure, 1.0);
 
-    let mut dt = 1.0;
+    let mut predictions = vec![last_measure];
+    let mut estimates = vec![last_measure];
 
-    let mut x = (
-        (last_measure,),
-        (0.0,)
-    );
+    for measure in &measurements[1..] {
+        filter.predict();
+        predictions.push(filter.x.0[0][0]);
 
-        
-    let mut p = (
-        (1.0, 0.0),
-        (0.0, 1.0)
-    );
+        filter.update(&Matrix([[*measure], [*measure - last_measure]]))?;
+        estimates.push(filter.x.0[0][0]);
 
-    let h = (
-        (1.0, 0.0),
-        (0.0, 1.0)
-    );
-    let r = (
-        (1.0, 0.0),
-        (0.0, 1.0)
-    );
+        last_measure = *measure;
+    }
 
-    let mut vec = Vec::<(u32, u32, u32)>::new();
-    vec.push((dt as u32, last_measure as u32, last_measure as u32));
+    Ok(Simulation { truth, measurements, predictions, estimates })
+}
 
-    let a = (
-        (1.0, 1.0),
-        (0.0, 1.0)
-    );
+/**
+Runs `simulate` with a generator seeded by `seed` and saves its drawing to `path`.
+*/
+pub fn line<P: AsRef<Path>>(path: P, seed: u64) -> crate::error::Result<Simulation> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let simulation = simulate(&mut rng)?;
+    simulation.draw().save(path)?;
+    Ok(simulation)
+}
 
-    for measure in measurement_iter {
+const LINE_LENGTH: usize = 500;
 
-        
-        let (t_x, t_p) = crate::kalman::predict(&x, &p, &a);
-        x = t_x;
-        p = t_p;
+/**
+A line rising by one pixel every 10 steps from row 250.
+*/
+fn true_line(len: usize) -> Vec<f32> {
+    (1..=len).map(|y| 250.0 + ((y - 1) / 10) as f32).collect()
+}
 
-        let y = ( 
-            (*measure, ),
-            (*measure - last_measure, )
-        );
+/**
+`true_line` with integer noise of up to 3 pixels, and up to 6 pixels from step 101 to 119.
+*/
+pub(crate) fn sample_line_gen<R: Rng>(rng: &mut R) -> Vec<f32> {
+    true_line(LINE_LENGTH)
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let y = i + 1;
+            let variability = match y > 100 && y < 120 {
+                true => rng.gen::<i16>() / 5000,
+                false => rng.gen::<i16>() / 10000
+            };
+            x + variability as f32
+        })
+        .collect()
+}
 
-        vec.push(
-            (dt as u32, *measure as u32, x.0.0 as u32)
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let (t_x, t_p) = crate::kalman::update(&x, &p, &y, &h, &r);    
-        x = t_x;
-        p = t_p;
+    #[test]
+    fn test_simulation_is_reproducible() {
+        let a = simulate(&mut StdRng::seed_from_u64(1)).unwrap();
+        let b = simulate(&mut StdRng::seed_from_u64(1)).unwrap();
+        let c = simulate(&mut StdRng::seed_from_u64(2)).unwrap();
 
-        last_measure = *measure;      
-        dt += 1.0;  
-        
-        
+        assert_eq!(a, b);
+        assert_ne!(a.measurements, c.measurements);
+        assert_eq!(a.truth, c.truth);
+        assert_eq!([a.measurements.len(), a.predictions.len(), a.estimates.len()], [500; 3]);
     }
 
-    for p in vec {
-        img.put_pixel(p.0, p.1, Rgb::<u8>([255, 0, 0])); 
-        img.put_pixel(p.0, p.2, Rgb::<u8>([0, 0, 255]));                
+    #[test]
+    fn test_sample_line_noise_is_bounded() {
+        let line = sample_line_gen(&mut StdRng::seed_from_u64(0));
+        let truth = true_line(line.len());
+
+        assert_eq!((truth[0], truth[9], truth[10], truth[499]), (250.0, 250.0, 251.0, 299.0));
+        for (i, (x, t)) in line.iter().zip(&truth).enumerate() {
+            let bound = match (100..119).contains(&i) {
+                true => 6.0,
+                false => 3.0
+            };
+            assert!((x - t).abs() <= bound, "{} {} {}", i, x, t);
+        }
     }
 
-    img.save("score_sample/simulated_kalman_filter.png")
-}
+    #[test]
+    fn test_residuals() {
+        let res = Residuals::between(&[1.0, 3.0, 2.0, 2.0], &[2.0, 2.0, 2.0, 2.0]);
 
-fn sample_line_gen() -> Vec<f32> {
-    let inc = 1.0;
-    let step = 10;
-    let mut x = 250.0;
-    let mut line = Vec::new();
+        assert_eq!(res, Residuals { mean: 0.0, std_dev: 0.5f32.sqrt(), rms: 0.5f32.sqrt(), max: 1.0 });
+        assert_eq!(Residuals::between(&[], &[]), Residuals::default());
+    }
 
-    for y in 1..501 {
-        let variability = match y > 100 && y < 120 {
-            true => rand::random::<i16>()/5000,
-            false => rand::random::<i16>()/10000
-        };
-                
-        line.push(
-           x + variability as f32
-        );
-        if y%step == 0 {x = x + inc;}
+    #[test]
+    fn test_filter_reduces_the_noise() {
+        for seed in 0..10 {
+            let simulation = simulate(&mut StdRng::seed_from_u64(seed)).unwrap();
+            let (errors, noise, innovations) = (simulation.errors(), simulation.noise(), simulation.innovations());
+
+            assert!(errors.rms < noise.rms, "seed {} {:?} {:?}", seed, errors, noise);
+            assert!(errors.mean.abs() < 0.5, "seed {} {:?}", seed, errors);
+            assert!(innovations.mean.abs() < 0.5, "seed {} {:?}", seed, innovations);
+        }
     }
 
-    line
-}
\ No newline at end of file
+    #[test]
+    fn test_line_saves_the_drawing() {
+        let path = std::env::temp_dir().join(format!("rustscanscore_{}_simulation.png", std::process::id()));
+
+        let simulation = line(&path, 3).unwrap();
+        let img = image::open(&path).unwrap().into_rgb8();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(simulation, simulate(&mut StdRng::seed_from_u64(3)).unwrap());
+        assert_eq!(img, simulation.draw());
+        assert_eq!(*img.get_pixel(1, simulation.estimates[0] as u32), Rgb([0, 0, 255]));
+    }
+}
@@ -1,112 +1,417 @@
+use std::collections::HashMap;
+
 use log::{debug, trace};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::error::{Error, Result};
+use crate::kalman::KalmanFilter;
+use crate::matrix::Matrix;
 
 #[derive(Debug)]
 struct Prediction {    
     from_y: f32,
     x: f32,
-    bias: f32
+    variance: f32,
+    thickness: f32
+}
+
+/**
+gate : Maximum Mahalanobis distance between a pixel centre and a predicted position,
+in standard deviations of the innovation.
+max_run_height : Taller vertical runs belong to symbols and are not matched.
+max_gap : Number of columns a staff can go without pixels before it stops predicting.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerances {
+    pub gate: f32,
+    pub max_run_height: usize,
+    pub max_gap: usize
+}
+
+impl Default for Tolerances {
+    fn default() -> Tolerances {
+        Tolerances {
+            gate: 1.4,
+            max_run_height: usize::MAX,
+            max_gap: usize::MAX
+        }
+    }
+}
+
+/**
+How a staff position is extrapolated to the next column.
+
+ConstantVelocity : The line keeps its last estimated slope.
+ConstantPosition : The line stays at its last position, for deskewed pages.
+ConstantAcceleration : The line bends with its estimated acceleration, for curved lines on warped scans.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MotionModel {
+    #[default]
+    ConstantVelocity,
+    ConstantPosition,
+    ConstantAcceleration
+}
+
+impl MotionModel {
+
+    /**
+    Transition of the position, speed and acceleration of a staff,
+    the acceleration only moving the line with `ConstantAcceleration`.
+
+    dt : Number of columns since the last update.
+    */
+    pub fn transition(&self, dt: f32) -> Matrix<3, 3> {
+        match self {
+            MotionModel::ConstantVelocity => Matrix([[1.0, dt, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]),
+            MotionModel::ConstantPosition => Matrix::identity(),
+            MotionModel::ConstantAcceleration => crate::kalman::constant_acceleration_transition(dt)
+        }
+    }
+
+}
+
+/**
+Tentative : A new track, dropped as soon as it misses a column.
+Confirmed : A track matched on enough columns, and on the last one.
+Coasting : A confirmed track predicting through a gap.
+Dead : A track that no longer predicts nor matches.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackStatus {
+    Tentative,
+    Confirmed,
+    Coasting,
+    Dead
+}
+
+/**
+Kalman filter parameters shared by every tracked line.
+
+q : Process noise covariance added at every prediction, on position, speed and acceleration.
+r : Measurement noise covariance of the pixel centre and its slope.
+initial_p : State covariance of a new track, on position, speed and acceleration.
+tolerances : Matching tolerances between pixels and predictions.
+motion : The transition model.
+diagonal_covariance : Drop the position and speed covariance at every prediction, as the first tracker did.
+birth_cost : Assignment cost of starting a new staff from a run, see `match_cost`.
+miss_cost : Assignment cost of a staff left without run in a column it could match.
+confirm_length : Number of matched columns for a tentative staff to be confirmed.
+min_length : Shorter staves are dropped once the page is tracked.
+merge_gate : Maximum Mahalanobis distance between the extrapolated end of a staff and the start of a later one
+for both to be merged, see `merge_fragments`. Zero disables merging.
+merge_max_gap : Maximum number of columns between two staves to be merged.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackerConfig {
+    pub q: Matrix<3, 3>,
+    pub r: Matrix<2, 2>,
+    pub initial_p: Matrix<3, 3>,
+    pub tolerances: Tolerances,
+    pub motion: MotionModel,
+    pub diagonal_covariance: bool,
+    pub birth_cost: f32,
+    pub miss_cost: f32,
+    pub confirm_length: usize,
+    pub min_length: usize,
+    pub merge_gate: f32,
+    pub merge_max_gap: usize
+}
+
+impl Default for TrackerConfig {
+    fn default() -> TrackerConfig {
+        TrackerConfig {
+            q: Matrix::zeros(),
+            r: Matrix::identity(),
+            initial_p: Matrix::identity(),
+            tolerances: Tolerances::default(),
+            motion: MotionModel::default(),
+            diagonal_covariance: false,
+            birth_cost: 4.0,
+            miss_cost: 4.0,
+            confirm_length: 1,
+            min_length: 1,
+            merge_gate: 3.0,
+            merge_max_gap: 256
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Staff {
-    x: crate::kalman::M2x1,
-    p: crate::kalman::M2x2,
-    pub buffer: Vec<(Vec<usize>, usize)>
+    x: Matrix<3, 1>,
+    p: Matrix<3, 3>,
+    pub buffer: Vec<(Vec<usize>, usize)>,
+    predictions: Vec<(usize, f32)>,
+    status: TrackStatus,
+    filtered: Vec<(Matrix<3, 1>, Matrix<3, 3>)>,
+    predicted: Vec<(Matrix<3, 1>, Matrix<3, 3>, Matrix<3, 3>)>,
+    smoothed: Vec<(usize, f32)>,
+    gaps: Vec<(usize, usize)>
 }
 
 impl Staff {
 
-    fn new(xs: Vec<usize>, y: usize) -> Staff {   
+    fn new(xs: Vec<usize>, y: usize, config: &TrackerConfig) -> Result<Staff> {   
         
-        let mean = Staff::get_mean(&xs).unwrap();
+        let mean = Staff::get_mean(&xs).ok_or(Error::EmptyTrack)?;
 
         debug!("Staff created at mean position x:{:?}", mean);
 
-        Staff {
-            x: (
-                (mean,),
-                (0.0,)
-            ),
-            p: (
-                (1.0, 0.0),
-                (0.0, 1.0)
-            ),
-            buffer: vec![(xs, y)]
-        }
+        let x = Matrix([[mean], [0.0], [0.0]]);
+
+        Ok(Staff {
+            x,
+            p: config.initial_p,
+            buffer: vec![(xs, y)],
+            predictions: Vec::new(),
+            status: match config.confirm_length {
+                0 | 1 => TrackStatus::Confirmed,
+                _ => TrackStatus::Tentative
+            },
+            filtered: vec![(x, config.initial_p)],
+            predicted: Vec::new(),
+            smoothed: Vec::new(),
+            gaps: Vec::new()
+        })
     }
     
-    const H:crate::kalman::M2x2 = (
-        (1.0, 0.0),
-        (0.0, 1.0)
-    );
-    const R:crate::kalman::M2x2 = (
-        (1.0, 0.0),
-        (0.0, 1.0)
-    );
-
-
-    fn get_prediction(&self, y: usize) -> Prediction {
-        let last_y = self.buffer.last().unwrap().1 as f32;
-        
-        let a = (
-            (1.0, y as f32 - last_y),
-            (0.0, 1.0)
-        );
+    /**
+    The pixel centre and its slope are measured, not the acceleration.
+    */
+    const H: Matrix<2, 3> = Matrix([
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0]
+    ]);
+
+    /**
+    The filter of the staff at its last column, transitioning over dt columns.
+    */
+    fn filter(&self, dt: f32, config: &TrackerConfig) -> KalmanFilter<3, 2> {
+        KalmanFilter {
+            x: self.x,
+            p: self.p,
+            f: config.motion.transition(dt),
+            q: config.q,
+            h: Staff::H,
+            r: config.r
+        }
+    }
 
-        let (t_x, _) = crate::kalman::predict(&self.x, &self.p, &a);
+    fn predict(&self, dt: f32, config: &TrackerConfig) -> KalmanFilter<3, 2> {
+        let mut filter = self.filter(dt, config);
+        match config.diagonal_covariance {
+            true => filter.predict_diagonal(),
+            false => filter.predict()
+        }
+        filter
+    }
+
+    fn get_prediction(&self, y: usize, config: &TrackerConfig) -> Result<Prediction> {
+        let last_y = self.buffer.last().ok_or(Error::EmptyTrack)?.1 as f32;
+        
+        let filter = self.predict(y as f32 - last_y, config);
 
-        debug!("Staff {:?} predict x:{:?} from column:{:?}", self.x, t_x.0.0, last_y);
+        debug!("Staff {:?} predict x:{:?} from column:{:?}", self.x, filter.x.0[0][0], last_y);
 
-        Prediction {
+        Ok(Prediction {
             from_y: last_y,
-            x: t_x.0.0,
-            bias: t_x.1.0
-        }
+            x: filter.x.0[0][0],
+            variance: filter.p.0[0][0] + config.r.0[0][0],
+            thickness: self.thickness()
+        })
                 
     }
 
-    fn push_pixels(&mut self, xs: Vec<usize>, y: usize) {
+    fn push_pixels(&mut self, xs: Vec<usize>, y: usize, config: &TrackerConfig) -> Result<()> {
         
-        let default = (xs.clone(), y.clone());
+        let default = (xs.clone(), y);
 
         let last_pixels = self.buffer.last().unwrap_or(&default);
 
-        let x_mean = Staff::get_mean(&xs).unwrap();
-        let last_x_mean = Staff::get_mean(&last_pixels.0).unwrap();
+        let x_mean = Staff::get_mean(&xs).ok_or(Error::EmptyTrack)?;
+        let last_x_mean = Staff::get_mean(&last_pixels.0).ok_or(Error::EmptyTrack)?;
 
-        let a = (
-            (1.0,  y as f32 - last_pixels.1 as f32),
-            (0.0, 1.0)
-        );
+        let dt = y as f32 - last_pixels.1 as f32;
+        let mut filter = self.predict(dt, config);
 
-        let (t_x, t_p) =
-        crate::kalman::predict(&self.x, &self.p, &a);
+        self.predictions.push((y, filter.x.0[0][0]));
+        self.predicted.push((filter.x, filter.p, filter.f));
         
-        let speed = 
-            x_mean - last_x_mean
-            /
-            (y as f32 - last_pixels.1 as f32);
-
-        let measure = ( 
-            (x_mean as f32, ),
-            (speed, )
-        );
+        // The slope of the line since its last pixels, over however many columns were missed.
+        let speed = (x_mean - last_x_mean) / dt;
 
-        let (t_x, t_p) =
-        crate::kalman::update(&t_x, &t_p, &measure, &Staff::H, &Staff::R);   
+        filter.update(&Matrix([[x_mean], [speed]]))?;
 
-        debug!("Staff {:?} updated with xs:{:?} y:{:?} and become:{:?}", self.x, xs, y, t_x);
+        debug!("Staff {:?} updated with xs:{:?} y:{:?} and become:{:?}", self.x, xs, y, filter.x);
         
-        self.x = t_x;
-        self.p = t_p;
+        self.x = filter.x;
+        self.p = filter.p;
 
         self.buffer.push((xs, y));
+        self.filtered.push((filter.x, filter.p));
+        self.smoothed.clear();
+
+        if self.status == TrackStatus::Coasting || self.buffer.len() >= config.confirm_length {
+            self.status = TrackStatus::Confirmed;
+        }
+
+        Ok(())
+    }
 
+    /**
+    Moves the staff to column y, before it is matched: a tentative staff dies on its first missed column,
+    a confirmed one coasts until it misses more than `Tolerances::max_gap` columns.
+    */
+    fn advance(&mut self, y: usize, config: &TrackerConfig) {
+        let missed = y.saturating_sub(self.end() + 1);
+        if missed == 0 || self.status == TrackStatus::Dead {return;}
+
+        let status = match self.status {
+            TrackStatus::Tentative => TrackStatus::Dead,
+            _ if missed > config.tolerances.max_gap => TrackStatus::Dead,
+            _ => TrackStatus::Coasting
+        };
+
+        if status != self.status {
+            trace!("Staff ending at column:{:?} goes from {:?} to {:?}", self.end(), self.status, status);
+            self.status = status;
+        }
+    }
+
+    pub fn status(&self) -> TrackStatus {
+        self.status
+    }
+
+    /**
+    Runs the Rauch–Tung–Striebel smoother backward over the track and stores the line centre
+    of every column from start to end, gaps being bridged by the smoothed speed and acceleration.
+    */
+    pub fn smooth(&mut self) -> Result<()> {
+        let states = self.smoothed_states()?;
+
+        let mut smoothed = Vec::new();
+        for (i, ((_, y), (x, _))) in self.buffer.iter().zip(states.iter()).enumerate() {
+            let next = self.buffer.get(i + 1).map_or(*y + 1, |(_, next)| *next);
+            smoothed.extend((*y..next).map(|column| {
+                let dt = (column - y) as f32;
+                (column, x.0[0][0] + x.0[1][0] * dt + x.0[2][0] * dt * dt / 2.0)
+            }));
+        }
+        self.smoothed = smoothed;
+
+        Ok(())
+    }
+
+    /**
+    Columns, first and last included, bridged when fragments of this staff were merged.
+    */
+    pub fn gaps(&self) -> &[(usize, usize)] {
+        &self.gaps
+    }
+
+    /**
+    Smoothed state estimate and covariance of every matched column.
+    */
+    fn smoothed_states(&self) -> Result<Vec<(Matrix<3, 1>, Matrix<3, 3>)>> {
+        let predicted = self.predicted.iter().map(|(x, p, _)| (*x, *p)).collect::<Vec<_>>();
+        let transitions = self.predicted.iter().map(|(_, _, f)| *f).collect::<Vec<_>>();
+
+        crate::kalman::smooth(&self.filtered, &predicted, &transitions)
+    }
+
+    /**
+    Smoothed state estimate and covariance at the first column.
+    */
+    fn start_state(&self) -> Result<(Matrix<3, 1>, Matrix<3, 3>)> {
+        self.smoothed_states()?
+            .first()
+            .copied()
+            .ok_or(Error::EmptyTrack)
+    }
+
+    /**
+    Squared Mahalanobis distance between this staff extrapolated to the column `start` of a later one,
+    and the smoothed state at the start of the later one, on both position and speed.
+    */
+    fn join_distance(&self, start: usize, (x_start, p_start): &(Matrix<3, 1>, Matrix<3, 3>), config: &TrackerConfig) -> Result<f32> {
+        let mut filter = self.filter((start - self.end()) as f32, config);
+        filter.predict();
+
+        let d = Staff::H * (*x_start - filter.x);
+        let s = Staff::H * (filter.p + *p_start) * Staff::H.transpose();
+
+        Ok((d.transpose() * s.inverse()? * d).0[0][0])
+    }
+
+    /**
+    Sub-pixel line centre of every column from start to end, empty until `smooth` is run.
+    */
+    pub fn smoothed(&self) -> &[(usize, f32)] {
+        &self.smoothed
     }
 
-    fn get_mean(xs: &Vec<usize>) -> Option<f32> {
+    /**
+    Last Kalman state estimate: position, speed and acceleration.
+    */
+    pub fn state(&self) -> &Matrix<3, 1> {
+        &self.x
+    }
+
+    /**
+    Last Kalman state covariance.
+    */
+    pub fn covariance(&self) -> &Matrix<3, 3> {
+        &self.p
+    }
+
+    /**
+    Position predicted by the Kalman filter for every column, before its pixels were pushed.
+    */
+    pub fn predictions(&self) -> &[(usize, f32)] {
+        &self.predictions
+    }
+
+    pub fn start(&self) -> usize {
+        self.buffer.first().map_or(0, |(_, y)| *y)
+    }
+
+    pub fn end(&self) -> usize {
+        self.buffer.last().map_or(0, |(_, y)| *y)
+    }
+
+    /**
+    Pixel centre of the line for every column the staff was matched on.
+    */
+    pub fn centres(&self) -> Vec<(usize, f32)> {
+        self.buffer
+            .iter()
+            .filter_map(|(xs, y)| Staff::get_mean(xs).map(|x| (*y, x)))
+            .collect()
+    }
+
+    /**
+    Mean pixel centre of the line.
+    */
+    pub fn position(&self) -> f32 {
+        let centres = self.centres();
+        centres.iter().map(|(_, x)| x).sum::<f32>() / centres.len().max(1) as f32
+    }
+
+    /**
+    Mean number of pixels matched per column.
+    */
+    pub fn thickness(&self) -> f32 {
+        match self.buffer.len() {
+            0 => 0.0,
+            len => self.buffer.iter().map(|(xs, _)| xs.len()).sum::<usize>() as f32 / len as f32
+        }
+    }
+
+    fn get_mean(xs: &[usize]) -> Option<f32> {
         let len = xs.len() as f32;
-        match xs.as_slice() {
+        match xs {
             [] => None,
             _ => Some(
                 (xs.iter().sum::<usize>() as f32 + len * 0.5)
@@ -118,7 +423,23 @@ impl Staff {
 
 }
 
-pub fn detect_staves(buffer_vertical:Vec<u8>, height: usize) -> Vec<Staff> {
+pub fn detect_staves(buffer_vertical:Vec<u8>, height: usize) -> Result<Vec<Staff>> {
+    detect_staves_with(buffer_vertical, height, &TrackerConfig::default())
+}
+
+pub fn detect_staves_with(buffer_vertical:Vec<u8>, height: usize, config: &TrackerConfig) -> Result<Vec<Staff>> {
+    if height == 0 || !buffer_vertical.len().is_multiple_of(height) {
+        return Err(Error::InvalidDimensions { len: buffer_vertical.len(), height });
+    }
+
+    let staves = track_columns(&buffer_vertical, height, 0, config)?;
+    finish_staves(staves, config)
+}
+
+/**
+Tracks lines column by column, `offset` being the number of page columns before the buffer.
+*/
+fn track_columns(buffer_vertical: &[u8], height: usize, offset: usize, config: &TrackerConfig) -> Result<Vec<Staff>> {
     let mut staves = Vec::<Staff>::new();
 
     for (y, buff) in buffer_vertical.chunks(height).enumerate() {
@@ -130,95 +451,421 @@ pub fn detect_staves(buffer_vertical:Vec<u8>, height: usize) -> Vec<Staff> {
             .map(|(x, _)| x + 1)
             .collect::<Vec<usize>>();
 
-        if pixel_positions.len() == 0 {continue;}
+        let runs = group_by_adjacent_values(pixel_positions)
+            .into_iter()
+            .filter(|run| run.len() <= config.tolerances.max_run_height)
+            .collect::<Vec<Vec<usize>>>();
 
-        let y = y + 1;
+        if runs.is_empty() {continue;}
+
+        let y = offset + y + 1;
 
         debug!("#################");
-        debug!("Start matching column:{:?} with pixel positions:{:?}", y, pixel_positions);
+        debug!("Start matching column:{:?} with runs:{:?}", y, runs);
         
-        let staff_predictions = staves
-            .iter()
-            .map(|staff| staff.get_prediction(y))
-            .collect::<Vec<Prediction>>();
+        staves.iter_mut().for_each(|staff| staff.advance(y, config));
 
-        let matches = pixel_positions
+        let active = (0..staves.len())
+            .filter(|s| staves[*s].status != TrackStatus::Dead)
+            .collect::<Vec<usize>>();
+
+        let staff_predictions = active
             .iter()
-            .map(
-                |x|
-                match_position(&staff_predictions, x, &y)
-            )
-            .collect::<Vec<Option<usize>>>();
+            .map(|s| staves[*s].get_prediction(y, config))
+            .collect::<Result<Vec<Prediction>>>()?;
+
+        for (piece, m) in match_runs(&runs, &staff_predictions, y, config) {
+            match m {
+                Some((s, xs)) => staves[active[s]].push_pixels(xs, y, config)?,
+                None => staves.push(Staff::new(piece, y, config)?)
+            }
+        }
 
+    }
 
-        debug!("Match positions:{:?} give:{:?}", pixel_positions, matches);
+    // Staves ending before blank columns miss them too.
+    let end = offset + buffer_vertical.len() / height + 1;
+    staves.iter_mut().for_each(|staff| staff.advance(end, config));
 
-        let matched_pixels = matches.iter()
-            .enumerate()
-            .filter(|(_, opt)| opt.is_some())
-            .map(|(i, opt)| (pixel_positions[i], opt.unwrap()))
-            .collect::<Vec<(usize, usize)>>();
+    Ok(staves)
+}
+
+/**
+A piece of run, with the index of the prediction it is assigned to and the matched pixels,
+None when it starts a new staff.
+*/
+type RunMatch = (Vec<usize>, Option<(usize, Vec<usize>)>);
+
+/**
+Assigns the runs of column y to the staff predictions, minimizing the total `match_cost`.
+*/
+fn match_runs(runs: &[Vec<usize>], predictions: &[Prediction], y: usize, config: &TrackerConfig) -> Vec<RunMatch> {
+    let pieces = runs
+        .iter()
+        .flat_map(|run| split_run(run, predictions, y, &config.tolerances))
+        .collect::<Vec<Vec<usize>>>();
+
+    let windows = pieces
+        .iter()
+        .map(|piece|
+            predictions
+                .iter()
+                .map(|pred| gate_run(pred, piece, y, &config.tolerances))
+                .collect::<Vec<Option<(Vec<usize>, f32)>>>()
+        )
+        .collect::<Vec<Vec<Option<(Vec<usize>, f32)>>>>();
+
+    let costs = windows
+        .iter()
+        .map(|row| row.iter().map(|w| w.as_ref().map(|(_, cost)| *cost)).collect())
+        .collect::<Vec<Vec<Option<f32>>>>();
+
+    let matches = crate::assignment::assign(&costs, config.birth_cost, config.miss_cost);
+
+    debug!("Match pieces:{:?} give:{:?}", pieces, matches);
+
+    pieces
+        .into_iter()
+        .zip(windows)
+        .zip(matches)
+        .map(|((piece, mut row), m)| {
+            let matched = m.and_then(|s| row[s].take().map(|(xs, _)| (s, xs)));
+            (piece, matched)
+        })
+        .collect()
+}
 
-        for (s, xs) in group_by_2nd_tuple_value(matched_pixels) {
-            staves[s].push_pixels(xs, y);
+/**
+Merges the fragments of the confirmed lines, prunes the short ones and smooths the others.
+*/
+fn finish_staves(staves: Vec<Staff>, config: &TrackerConfig) -> Result<Vec<Staff>> {
+    let count = staves.len();
+    let staves = merge_fragments(confirmed_staves(staves, config), config)?
+        .into_iter()
+        .filter(|staff| staff.buffer.len() >= config.min_length.max(config.confirm_length))
+        .collect::<Vec<Staff>>();
+
+    debug!("Pruned {:?} short staves out of {:?}", count - staves.len(), count);
+
+    let mut staves = staves;
+    for staff in staves.iter_mut() {
+        staff.smooth()?;
+    }
+
+    Ok(staves)
+    
+}
+
+/**
+Staves matched on at least `TrackerConfig::confirm_length` columns, the only ones `merge_fragments` is given.
+*/
+fn confirmed_staves(staves: Vec<Staff>, config: &TrackerConfig) -> Vec<Staff> {
+    staves
+        .into_iter()
+        .filter(|staff| staff.buffer.len() >= config.confirm_length)
+        .collect()
+}
+
+/**
+Joins the fragments of a line broken by a wide gap: pairs of staves, the second starting after the first ends
+by at most `TrackerConfig::merge_max_gap` columns, whose states agree within `TrackerConfig::merge_gate`
+are merged, closest pairs first. Pairs whose covariances can not be inverted are not merged.
+The merged staff is filtered again over all its pixels and remembers the bridged gaps.
+*/
+pub fn merge_fragments(staves: Vec<Staff>, config: &TrackerConfig) -> Result<Vec<Staff>> {
+    if config.merge_gate <= 0.0 {return Ok(staves);}
+
+    let mut staves = staves;
+    staves.sort_by_key(|staff| staff.start());
+
+    // A staff whose start can not be smoothed is left out of the later ones.
+    let starts = staves.iter().map(|staff| staff.start_state().ok()).collect::<Vec<Option<(Matrix<3, 1>, Matrix<3, 3>)>>>();
+
+    let mut pairs = Vec::new();
+    for (i, first) in staves.iter().enumerate() {
+        let from = staves.partition_point(|staff| staff.start() <= first.end() + 1);
+        let to = staves.partition_point(|staff| staff.start() <= first.end() + 1 + config.merge_max_gap);
+
+        for j in from..to {
+            let d2 = match starts[j].as_ref().map(|start| first.join_distance(staves[j].start(), start, config)) {
+                Some(Ok(d2)) => d2,
+                _ => continue
+            };
+            if d2 <= config.merge_gate * config.merge_gate {
+                pairs.push((i, j, d2));
+            }
         }
+    }
+    pairs.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut next = vec![None; staves.len()];
+    let mut previous = vec![None; staves.len()];
+    for (i, j, _) in pairs {
+        if next[i].is_some() || previous[j].is_some() {continue;}
+        next[i] = Some(j);
+        previous[j] = Some(i);
+    }
 
-        
-        
-        let unmatched_pixels = matches
-            .iter()
-            .enumerate()    
-            .filter(|(_, opt)| opt.is_none())
-            .map(|(i, _)| pixel_positions[i])
-            .collect::<Vec<usize>>();
-        
-        for xs in group_by_adjacent_values(unmatched_pixels) {
-            staves.push(Staff::new(xs, y))
+    let mut fragments = staves.into_iter().map(Some).collect::<Vec<Option<Staff>>>();
+    let mut merged = Vec::new();
+
+    for first in 0..fragments.len() {
+        if previous[first].is_some() {continue;}
+
+        let mut chain = vec![first];
+        while let Some(j) = next[*chain.last().unwrap_or(&first)] {
+            chain.push(j);
+        }
+
+        if chain.len() == 1 {
+            merged.extend(fragments[first].take());
+            continue;
         }
 
+        let parts = chain.iter().filter_map(|i| fragments[*i].take()).collect::<Vec<Staff>>();
+        debug!("Merge fragments {:?}", parts.iter().map(|p| (p.start(), p.end())).collect::<Vec<(usize, usize)>>());
+        merged.push(join(parts, config)?);
     }
 
-    staves
-    
+    merged.sort_by_key(|staff| staff.start());
+    Ok(merged)
+}
+
+fn join(parts: Vec<Staff>, config: &TrackerConfig) -> Result<Staff> {
+    let gaps = parts
+        .windows(2)
+        .map(|w| (w[0].end() + 1, w[1].start() - 1))
+        .collect::<Vec<(usize, usize)>>();
+    let status = parts.last().map_or(TrackStatus::Dead, |p| p.status);
+
+    let mut staff = refilter(parts.into_iter().flat_map(|p| p.buffer), config)?;
+    staff.status = status;
+    staff.gaps = gaps;
+    Ok(staff)
+}
+
+/**
+A staff filtered over the given pixels of each column, in column order.
+*/
+fn refilter(columns: impl IntoIterator<Item = (Vec<usize>, usize)>, config: &TrackerConfig) -> Result<Staff> {
+    let mut columns = columns.into_iter();
+    let (xs, y) = columns.next().ok_or(Error::EmptyTrack)?;
+    let mut staff = Staff::new(xs, y, config)?;
+    for (xs, y) in columns {
+        staff.push_pixels(xs, y, config)?;
+    }
+    Ok(staff)
+}
+
+/**
+width : Number of page columns owned by each strip, a page is split when wider than two strips.
+overlap : Number of columns tracked on each side of a strip beyond the owned ones,
+for lines to be established at the strip edges and shared with the neighbour strips.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Strips {
+    pub width: usize,
+    pub overlap: usize
+}
+
+impl Default for Strips {
+    fn default() -> Strips {
+        Strips {
+            width: 1024,
+            overlap: 256
+        }
+    }
+}
+
+/**
+Tracks a wide page in overlapping vertical strips, concurrently with the `parallel` feature.
+The tracks of two neighbour strips sharing the most pixels in their overlap are stitched,
+each column keeping the pixels tracked by the strip owning it.
+*/
+pub fn detect_staves_in_strips(buffer_vertical: Vec<u8>, height: usize, config: &TrackerConfig, strips: &Strips) -> Result<Vec<Staff>> {
+    if height == 0 || !buffer_vertical.len().is_multiple_of(height) {
+        return Err(Error::InvalidDimensions { len: buffer_vertical.len(), height });
+    }
+
+    let columns = buffer_vertical.len() / height;
+    if strips.width == 0 || columns <= 2 * strips.width {
+        return detect_staves_with(buffer_vertical, height, config);
+    }
+
+    let count = columns / strips.width;
+    let owned = (0..count)
+        .map(|k| (k * strips.width, if k + 1 == count {columns} else {(k + 1) * strips.width}))
+        .collect::<Vec<(usize, usize)>>();
+
+    debug!("Track {:?} columns in strips:{:?}", columns, owned);
+
+    let track = |(start, end): &(usize, usize)| {
+        let from = start.saturating_sub(strips.overlap);
+        let to = (end + strips.overlap).min(columns);
+        track_columns(&buffer_vertical[from * height..to * height], height, from, config)
+    };
+
+    #[cfg(feature = "parallel")]
+    let tracked = owned.par_iter().map(track).collect::<Result<Vec<Vec<Staff>>>>()?;
+    #[cfg(not(feature = "parallel"))]
+    let tracked = owned.iter().map(track).collect::<Result<Vec<Vec<Staff>>>>()?;
+
+    let staves = stitch(&tracked, &owned, config)?;
+    finish_staves(staves, config)
 }
 
-fn match_position(predictions: &Vec<Prediction>, x: &usize, y: &usize) -> Option<usize> {
-    let mut result = predictions
+fn shared_pixels(a: &Staff, b: &Staff) -> usize {
+    if a.end() < b.start() || b.end() < a.start() {return 0;}
+
+    let columns = a.buffer.iter().map(|(xs, y)| (*y, xs)).collect::<HashMap<usize, &Vec<usize>>>();
+    b.buffer
         .iter()
-        .enumerate()
-        .filter(|(_, pred)| (*x as f32 + 0.5 - pred.x).abs() <= 1.4)
-        .map(
-            |(id, pred)|
-            (id, *y as f32 - pred.from_y, pred.bias.abs()))
-        .collect::<Vec<(usize, f32, f32)>>();
+        .filter_map(|(xs, y)| columns.get(y).map(|other| xs.iter().filter(|x| other.contains(x)).count()))
+        .sum()
+}
 
-    result.sort_by(
-        |a,b| 
-        a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal)
-    );
+/**
+The track of the right strip continuing each track of the left strip, pairs sharing the most pixels first.
+*/
+fn link_tracks(left: &[Staff], right: &[Staff]) -> Vec<Option<usize>> {
+    let mut pairs = Vec::new();
+    for (i, a) in left.iter().enumerate() {
+        for (j, b) in right.iter().enumerate() {
+            let shared = shared_pixels(a, b);
+            if shared > 0 {pairs.push((i, j, shared));}
+        }
+    }
+    pairs.sort_by_key(|(_, _, shared)| std::cmp::Reverse(*shared));
+
+    let mut links = vec![None; left.len()];
+    let mut linked = vec![false; right.len()];
+    for (i, j, _) in pairs {
+        if links[i].is_some() || linked[j] {continue;}
+        links[i] = Some(j);
+        linked[j] = true;
+    }
+    links
+}
 
-    trace!("Matching x:{:?} y:{:?} from predictions:{:?} give:{:?}", x, y, predictions, result);
+fn stitch(tracked: &[Vec<Staff>], owned: &[(usize, usize)], config: &TrackerConfig) -> Result<Vec<Staff>> {
+    let links = tracked.windows(2).map(|w| link_tracks(&w[0], &w[1])).collect::<Vec<Vec<Option<usize>>>>();
 
+    let mut continued = tracked.iter().map(|t| vec![false; t.len()]).collect::<Vec<Vec<bool>>>();
+    for (k, strip_links) in links.iter().enumerate() {
+        strip_links.iter().flatten().for_each(|j| continued[k + 1][*j] = true);
+    }
 
-    match result.first() {
-        None => None,
-        Some(r) => Some(r.0)
+    let mut staves = Vec::new();
+    for (k, strip) in tracked.iter().enumerate() {
+        for i in (0..strip.len()).filter(|i| !continued[k][*i]) {
+            let mut columns = Vec::new();
+            let mut status = TrackStatus::Dead;
+            let mut link = Some((k, i));
+
+            while let Some((s, t)) = link {
+                let staff = &tracked[s][t];
+                let (start, end) = owned[s];
+                columns.extend(staff.buffer.iter().filter(|(_, y)| *y > start && *y <= end).cloned());
+                status = staff.status;
+                link = links.get(s).and_then(|l| l[t]).map(|j| (s + 1, j));
+            }
+
+            if columns.is_empty() {continue;}
+            let mut staff = refilter(columns, config)?;
+            staff.status = status;
+            staves.push(staff);
+        }
+    }
+
+    staves.sort_by_key(|staff| staff.start());
+    Ok(staves)
+}
+
+/**
+Splits a run crossed by several staves, such as a barline or a stem, into the disjoint parts each staff can match.
+Overlapping parts stay together so that two staves compete for them in the assignment.
+A run no staff can match is kept whole, pixels of a crossed run outside every staff window are dropped.
+*/
+fn split_run(run: &[usize], predictions: &[Prediction], y: usize, tolerances: &Tolerances) -> Vec<Vec<usize>> {
+    let mut windows = predictions
+        .iter()
+        .filter_map(|pred| gate_run(pred, run, y, tolerances))
+        .map(|(xs, _)| xs)
+        .collect::<Vec<Vec<usize>>>();
+
+    if windows.is_empty() {return vec![run.to_vec()];}
+
+    windows.sort();
+
+    let mut pieces: Vec<Vec<usize>> = Vec::new();
+    for window in windows {
+        match pieces.last_mut() {
+            Some(last) if last.last() >= window.first() => {
+                let end = *last.last().unwrap_or(&0);
+                last.extend(window.into_iter().filter(|x| *x > end));
+            },
+            _ => pieces.push(window)
+        }
+    }
+    pieces
+}
+
+/**
+Pixels of a run close enough to a staff prediction to belong to the line, with their `match_cost`.
+At most one pixel more than the staff thickness is kept, nearest to the prediction,
+so that pixels of a symbol touching the line are left out of the measurement.
+*/
+fn gate_run(pred: &Prediction, run: &[usize], y: usize, tolerances: &Tolerances) -> Option<(Vec<usize>, f32)> {
+    let gated = run
+        .iter()
+        .filter(|x| match_cost(pred, **x as f32 + 0.5, y, tolerances).is_some())
+        .copied()
+        .collect::<Vec<usize>>();
+
+    let size = (pred.thickness.round() as usize + 1).min(gated.len());
+    let xs = gated
+        .windows(size.max(1))
+        .min_by(|a, b| {
+            let distance = |w: &[usize]| (Staff::get_mean(w).unwrap_or(f32::MAX) - pred.x).abs();
+            distance(a).partial_cmp(&distance(b)).unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map(|w| w.to_vec())
+        .unwrap_or_default();
+
+    let centre = Staff::get_mean(&xs)?;
+    match_cost(pred, centre, y, tolerances).map(|cost| (xs, cost))
+}
+
+/**
+Negative log-likelihood of a run centre given a staff prediction,
+None when the Mahalanobis distance is beyond the gate or the staff has been lost for too long.
+A confident staff costs less than a coasting one at the same distance.
+*/
+fn match_cost(pred: &Prediction, centre: f32, y: usize, tolerances: &Tolerances) -> Option<f32> {
+    if y as f32 - pred.from_y > tolerances.max_gap as f32 || pred.variance <= 0.0 {return None;}
+
+    let d2 = (centre - pred.x).powi(2) / pred.variance;
+
+    trace!("Matching centre:{:?} y:{:?} from prediction:{:?} give distance:{:?}", centre, y, pred, d2);
+
+    match d2 <= tolerances.gate * tolerances.gate {
+        true => Some(d2 + pred.variance.ln()),
+        false => None
     }
 }
 
 fn group_by_adjacent_values(vec: Vec<usize>) -> Vec<Vec<usize>> {
     let mut res:Vec<Vec<usize>> = Vec::new();
 
-    if vec.len() > 0 {
+    if !vec.is_empty() {
         res.push(vec![vec[0]]);
     }    
 
     for s in vec.windows(2) {         
         match s {
             [current, next] if next-current==1 => 
-                match res.last_mut() {
-                    Some(last) => last.push(*next),
-                    _ => ()
+                if let Some(last) = res.last_mut() {
+                    last.push(*next)
                 },
             [_, next] => res.push(vec![*next]),
             _ => ()
@@ -227,85 +874,23 @@ fn group_by_adjacent_values(vec: Vec<usize>) -> Vec<Vec<usize>> {
     res
 }
 
-fn group_by_2nd_tuple_value(vec: Vec<(usize, usize)>) -> Vec<(usize, Vec<usize>)> {
-    let mut res:Vec<(usize, Vec<usize>)> = Vec::new();
-       
-    let mut iter = vec;
-    
-    iter.sort_by(
-        |a, b| 
-        a.1.partial_cmp(&b.1)
-        .unwrap_or(std::cmp::Ordering::Equal)
-    );
-
-    if iter.len() > 0 {
-        res.push((iter[0].1, vec![iter[0].0]));
-    }
-
-    for s in iter.windows(2) {
-        match s {
-            [current, next] if current.1==next.1 =>
-                match res.last_mut() {
-                    Some(last) => last.1.push(next.0),
-                    _ => ()
-                },
-            [_, next] => res.push((next.1, vec![next.0])),
-            _ => ()
-         }
-    }
-
-    res
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use crate::tests::init_logger;
 
-    #[test]
-    fn test_group_by_2nd_tuple_value() {
-        let vec = vec![
-            (4, 3),
-            (3, 4),
-            (2, 3),
-            (1, 4)
-        ];
-        let res= vec![
-            (3, vec![4,2]),
-            (4, vec![3,1])         
-        ];
-
-        assert_eq!(group_by_2nd_tuple_value(vec), res);
-    }
-
-    #[test]
-    fn test_group_by_2nd_tuple_value_should_sort() {
-        let vec = vec![
-            (0, 4),
-            (3, 4),
-            (2, 4),
-            (1, 3),
-            (8, 4),
-        ];
-        let res= vec![
-            (3, vec![1]),
-            (4, vec![0,3,2,8])         
-        ];
-
-        assert_eq!(group_by_2nd_tuple_value(vec), res);
-    }
-
-    #[test]
-    fn test_group_by_2nd_tuple_value_return_empty_if_empty() {
-        let vec = Vec::new();
-        let res= Vec::new();
-
-        assert_eq!(group_by_2nd_tuple_value(vec), res);
+    /**
+    The prediction a single pixel is assigned to by `match_runs`.
+    */
+    fn match_position(predictions: &[Prediction], x: &usize, y: &usize, tolerances: &Tolerances) -> Option<usize> {
+        let config = TrackerConfig { tolerances: *tolerances, ..TrackerConfig::default() };
+        match match_runs(&[vec![*x]], predictions, *y, &config).as_slice() {
+            [(_, m)] => m.as_ref().map(|(s, _)| *s),
+            pieces => panic!("Unexpected pieces {:?}", pieces)
+        }
     }
 
-
-
     #[test]
     fn test_group_by_adjacent_values() {
         let vec = vec![0,2,3,4,6,7];
@@ -339,67 +924,107 @@ mod tests {
     #[test]
     fn test_match_position_use_pixel_center() {
         let predictions = vec![
-            Prediction {x: 5.0, from_y: 1.0, bias: 0.0},       
+            Prediction {x: 5.0, from_y: 1.0, variance: 1.0, thickness: 1.0},       
         ];
         let x = 4;
         let y = 2;
-        assert_eq!(match_position(&predictions, &x, &y), Some(0));
+        assert_eq!(match_position(&predictions, &x, &y, &Tolerances::default()), Some(0));
         let x = 6;
         let y = 2;
-        assert_eq!(match_position(&predictions, &x, &y), None);
+        assert_eq!(match_position(&predictions, &x, &y, &Tolerances::default()), None);
     }
 
     #[test]
-    fn test_match_position_foster_horizontality() {
+    fn test_match_position_foster_confident_staff() {
         init_logger();
         let predictions = vec![
-            Prediction {x: 5.5, from_y: 5.0, bias: 0.0},
-            Prediction {x: 7.458883, from_y: 5.0, bias: -0.6},  
-            Prediction {x: 6.217949, from_y: 5.0, bias: -0.6666667},      
+            Prediction {x: 5.5, from_y: 5.0, variance: 1.05, thickness: 1.0},
+            Prediction {x: 7.458883, from_y: 5.0, variance: 3.0, thickness: 1.0},  
+            Prediction {x: 6.217949, from_y: 5.0, variance: 3.0, thickness: 1.0},      
         ];
 
-        assert_eq!(match_position(&predictions, &4, &6), Some(0));
-        assert_eq!(match_position(&predictions, &5, &6), Some(0));
-        assert_eq!(match_position(&predictions, &6, &6), Some(0));
+        assert_eq!(match_position(&predictions, &4, &6, &Tolerances::default()), Some(0));
+        assert_eq!(match_position(&predictions, &5, &6, &Tolerances::default()), Some(0));
+        assert_eq!(match_position(&predictions, &6, &6, &Tolerances::default()), Some(0));
     }
 
     #[test]
-    fn test_match_position_foster_continuity_and_horizontality() {
+    fn test_match_position_foster_continuity() {
         let predictions = vec![
-            Prediction {x: 1.0, from_y: 1.0, bias: 1.0},
-            Prediction {x: 2.0, from_y: 3.0, bias: 0.0},        
+            Prediction {x: 1.0, from_y: 1.0, variance: 5.0, thickness: 1.0},
+            Prediction {x: 2.0, from_y: 3.0, variance: 1.5, thickness: 1.0},        
         ];
         let x = 1;
         let y = 4;
-        assert_eq!(match_position(&predictions, &x, &y), Some(1));
+        assert_eq!(match_position(&predictions, &x, &y, &Tolerances::default()), Some(1));
     }
      
   
     #[test]
     fn test_match_position_disadvantage_distant_staff() {
         let predictions = vec![
-            Prediction {x: 1., from_y: 1.0, bias: 0.0},    
-            Prediction {x: 2., from_y: 2.0, bias: 0.5}
+            Prediction {x: 1., from_y: 1.0, variance: 3.0, thickness: 1.0},    
+            Prediction {x: 2., from_y: 2.0, variance: 2.0, thickness: 1.0}
         ];
         let x = 1;
         let y = 3;
-        assert_eq!(match_position(&predictions, &x, &y), Some(1));
+        assert_eq!(match_position(&predictions, &x, &y, &Tolerances::default()), Some(1));
     }
 
     #[test]
-    fn test_match_position_follow_sorting_in_eq_condition() {
+    fn test_match_position_prefer_closest_prediction() {
         let pred1 = vec![      
-            Prediction {x: 2., from_y: 1.0, bias: 0.0},    
-            Prediction {x: 1.5, from_y: 1.0, bias: 0.0}  
+            Prediction {x: 2., from_y: 1.0, variance: 1.0, thickness: 1.0},    
+            Prediction {x: 1.5, from_y: 1.0, variance: 1.0, thickness: 1.0}  
         ];
         let pred2 = vec![        
-            Prediction {x: 1.5, from_y: 1.0, bias: 0.0},
-            Prediction {x: 2., from_y: 1.0, bias: 0.0}
+            Prediction {x: 1.5, from_y: 1.0, variance: 1.0, thickness: 1.0},
+            Prediction {x: 2., from_y: 1.0, variance: 1.0, thickness: 1.0}
         ];
         let x = 2;
         let y = 2;
-        assert_eq!(match_position(&pred1, &x, &y), Some(0));
-        assert_eq!(match_position(&pred2, &x, &y), Some(0));
+        assert_eq!(match_position(&pred1, &x, &y, &Tolerances::default()), Some(0));
+        assert_eq!(match_position(&pred2, &x, &y, &Tolerances::default()), Some(1));
+    }
+
+    #[test]
+    fn test_match_position_widen_gate_with_uncertainty() {
+        let confident = vec![Prediction {x: 5.0, from_y: 1.0, variance: 1.0, thickness: 1.0}];
+        let coasting = vec![Prediction {x: 5.0, from_y: 1.0, variance: 4.0, thickness: 1.0}];
+
+        assert_eq!(match_position(&confident, &7, &2, &Tolerances::default()), None);
+        assert_eq!(match_position(&coasting, &7, &2, &Tolerances::default()), Some(0));
+    }
+
+    #[test]
+    fn test_match_position_ignore_staff_beyond_max_gap() {
+        let predictions = vec![
+            Prediction {x: 2., from_y: 1.0, variance: 1.0, thickness: 1.0}
+        ];
+        let tolerances = Tolerances { max_gap: 3, ..Tolerances::default() };
+        assert_eq!(match_position(&predictions, &2, &4, &tolerances), Some(0));
+        assert_eq!(match_position(&predictions, &2, &5, &tolerances), None);
+    }
+
+    #[test]
+    fn test_detect_staves_skip_tall_runs() {
+        let height = 10;
+        let mut buffer = vec![255; 5 * height];
+        for c in 0..5 {
+            buffer[c * height + 2] = 0;
+        }
+        for r in 0..8 {
+            buffer[2 * height + r] = 0;
+        }
+        let config = TrackerConfig {
+            tolerances: Tolerances { max_run_height: 2, ..Tolerances::default() },
+            ..TrackerConfig::default()
+        };
+
+        let staves = detect_staves_with(buffer, height, &config).unwrap();
+
+        assert_eq!(staves.len(), 1);
+        assert_eq!(staves[0].buffer.iter().map(|(_, y)| *y).collect::<Vec<usize>>(), vec![1, 2, 4, 5]);
     }
 
     #[test]
@@ -413,11 +1038,370 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_create_empty_staff_should_panic() {
-        Staff::new(Vec::new(), 0);
+    fn test_create_empty_staff_should_fail() {
+        assert!(matches!(Staff::new(Vec::new(), 0, &TrackerConfig::default()), Err(Error::EmptyTrack)));
     }
 
-   
-}
+    #[test]
+    fn test_detect_staves_reject_invalid_dimensions() {
+        assert!(matches!(
+            detect_staves(vec![255; 10], 3),
+            Err(Error::InvalidDimensions { len: 10, height: 3 })
+        ));
+        assert!(matches!(
+            detect_staves(vec![255; 10], 0),
+            Err(Error::InvalidDimensions { len: 10, height: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_staff_start_with_initial_covariance() {
+        let config = TrackerConfig { initial_p: Matrix::diagonal([4.0, 2.0, 1.0]), ..TrackerConfig::default() };
+        let staff = Staff::new(vec![3], 1, &config).unwrap();
+
+        assert_eq!(staff.covariance(), &config.initial_p);
+    }
+
+    #[test]
+    fn test_process_noise_widen_prediction_uncertainty() {
+        let quiet = TrackerConfig::default();
+        let noisy = TrackerConfig { q: Matrix::diagonal([0.5, 0.5, 0.0]), ..TrackerConfig::default() };
+
+        let mut a = Staff::new(vec![3], 1, &quiet).unwrap();
+        let mut b = Staff::new(vec![3], 1, &noisy).unwrap();
+        a.push_pixels(vec![3], 2, &quiet).unwrap();
+        b.push_pixels(vec![3], 2, &noisy).unwrap();
+
+        assert!(b.covariance().0[0][0] > a.covariance().0[0][0]);
+    }
+
+    #[test]
+    fn test_constant_position_ignore_speed() {
+        let mut staff = Staff::new(vec![3], 1, &TrackerConfig::default()).unwrap();
+        staff.x = Matrix([[3.5], [1.0], [0.0]]);
+
+        let velocity = staff.get_prediction(5, &TrackerConfig::default()).unwrap();
+        let config = TrackerConfig { motion: MotionModel::ConstantPosition, ..TrackerConfig::default() };
+        let position = staff.get_prediction(5, &config).unwrap();
+
+        assert_eq!(velocity.x, 7.5);
+        assert_eq!(position.x, 3.5);
+    }
+
+    #[test]
+    fn test_constant_acceleration_bend_prediction() {
+        let mut staff = Staff::new(vec![3], 1, &TrackerConfig::default()).unwrap();
+        staff.x = Matrix([[3.5], [1.0], [0.5]]);
+
+        let velocity = staff.get_prediction(5, &TrackerConfig::default()).unwrap();
+        let config = TrackerConfig { motion: MotionModel::ConstantAcceleration, ..TrackerConfig::default() };
+        let acceleration = staff.get_prediction(5, &config).unwrap();
+
+        assert_eq!(velocity.x, 7.5);
+        assert_eq!(acceleration.x, 11.5);
+    }
 
+    #[test]
+    fn test_constant_acceleration_follow_curved_line() {
+        let curve = |y: usize| (10.0 + 0.01 * (y * y) as f32).round() as usize;
+        let error = |motion| {
+            let config = TrackerConfig { motion, ..TrackerConfig::default() };
+            let mut staff = Staff::new(vec![curve(1)], 1, &config).unwrap();
+            for y in 2..=80 {
+                staff.push_pixels(vec![curve(y)], y, &config).unwrap();
+            }
+            (staff.get_prediction(81, &config).unwrap().x - curve(81) as f32).abs()
+        };
+
+        let curved = error(MotionModel::ConstantAcceleration);
+        let straight = error(MotionModel::ConstantVelocity);
+        assert!(curved < 1.0, "{}", curved);
+        assert!(curved < straight, "{} {}", curved, straight);
+    }
+
+    #[test]
+    fn test_diagonal_covariance_is_opt_in() {
+        let mut full = Staff::new(vec![3], 1, &TrackerConfig::default()).unwrap();
+        full.push_pixels(vec![3], 3, &TrackerConfig::default()).unwrap();
+        assert!(full.covariance().0[0][1] != 0.0);
+
+        let config = TrackerConfig { diagonal_covariance: true, ..TrackerConfig::default() };
+        let mut diagonal = Staff::new(vec![3], 1, &config).unwrap();
+        diagonal.push_pixels(vec![3], 3, &config).unwrap();
+        assert_eq!(diagonal.covariance().0[0][1], 0.0);
+    }
+
+    #[test]
+    fn test_detect_staves_split_barline_between_lines() {
+        let height = 10;
+        let mut buffer = vec![255; 12 * height];
+        for c in 0..12 {
+            buffer[c * height + 2] = 0;
+            buffer[c * height + 6] = 0;
+        }
+        for r in 1..9 {
+            buffer[6 * height + r] = 0;
+        }
+
+        let staves = detect_staves(buffer, height).unwrap();
+
+        assert_eq!(staves.len(), 2);
+        for (staff, row) in staves.iter().zip([3, 7].iter()) {
+            assert_eq!((staff.start(), staff.end(), staff.buffer.len()), (1, 12, 12));
+            assert!(staff.centres().iter().all(|(_, x)| (x - (*row as f32 + 0.5)).abs() <= 0.5));
+        }
+    }
+
+    #[test]
+    fn test_split_run_keep_overlapping_windows_together() {
+        let predictions = vec![
+            Prediction {x: 3.5, from_y: 1.0, variance: 1.0, thickness: 1.0},
+            Prediction {x: 4.5, from_y: 1.0, variance: 1.0, thickness: 1.0},
+            Prediction {x: 9.5, from_y: 1.0, variance: 1.0, thickness: 1.0}
+        ];
+
+        assert_eq!(split_run(&[2, 3, 4, 5, 6, 7, 8, 9, 10], &predictions, 2, &Tolerances::default()), vec![vec![2, 3, 4], vec![8, 9]]);
+        assert_eq!(split_run(&[20, 21], &predictions, 2, &Tolerances::default()), vec![vec![20, 21]]);
+    }
+
+    #[test]
+    fn test_staff_lifecycle() {
+        let config = TrackerConfig {
+            confirm_length: 3,
+            tolerances: Tolerances { max_gap: 2, ..Tolerances::default() },
+            ..TrackerConfig::default()
+        };
+
+        let mut staff = Staff::new(vec![3], 1, &config).unwrap();
+        assert_eq!(staff.status(), TrackStatus::Tentative);
+        staff.push_pixels(vec![3], 2, &config).unwrap();
+        staff.push_pixels(vec![3], 3, &config).unwrap();
+        assert_eq!(staff.status(), TrackStatus::Confirmed);
+
+        staff.advance(4, &config);
+        assert_eq!(staff.status(), TrackStatus::Confirmed);
+        staff.advance(5, &config);
+        assert_eq!(staff.status(), TrackStatus::Coasting);
+        staff.push_pixels(vec![3], 5, &config).unwrap();
+        assert_eq!(staff.status(), TrackStatus::Confirmed);
+
+        staff.advance(9, &config);
+        assert_eq!(staff.status(), TrackStatus::Dead);
+    }
+
+    #[test]
+    fn test_tentative_staff_die_on_first_miss() {
+        let config = TrackerConfig { confirm_length: 3, ..TrackerConfig::default() };
+
+        let mut staff = Staff::new(vec![3], 1, &config).unwrap();
+        staff.advance(3, &config);
+
+        assert_eq!(staff.status(), TrackStatus::Dead);
+    }
+
+    #[test]
+    fn test_detect_staves_prune_noise() {
+        let height = 10;
+        let mut buffer = vec![255; 12 * height];
+        for c in 0..12 {
+            buffer[c * height + 2] = 0;
+        }
+        buffer[3 * height + 7] = 0;
+        buffer[4 * height + 7] = 0;
+
+        assert_eq!(detect_staves(buffer.clone(), height).unwrap().len(), 2);
+
+        let config = TrackerConfig { confirm_length: 3, min_length: 5, ..TrackerConfig::default() };
+        let staves = detect_staves_with(buffer, height, &config).unwrap();
+
+        assert_eq!(staves.len(), 1);
+        assert_eq!(staves[0].status(), TrackStatus::Confirmed);
+    }
+
+    #[test]
+    fn test_dead_staff_stop_matching() {
+        let height = 10;
+        let mut buffer = vec![255; 12 * height];
+        for c in (0..4).chain(8..12) {
+            buffer[c * height + 2] = 0;
+        }
+        let config = TrackerConfig {
+            tolerances: Tolerances { max_gap: 2, ..Tolerances::default() },
+            merge_gate: 0.0,
+            ..TrackerConfig::default()
+        };
+
+        let staves = detect_staves_with(buffer, height, &config).unwrap();
+
+        assert_eq!(staves.len(), 2);
+        assert_eq!(staves[0].status(), TrackStatus::Dead);
+        assert_eq!((staves[1].start(), staves[1].end()), (9, 12));
+    }
+
+    #[test]
+    fn test_speed_measured_over_missed_columns() {
+        let config = TrackerConfig::default();
+        let mut staff = Staff::new(vec![3], 1, &config).unwrap();
+        staff.push_pixels(vec![5], 3, &config).unwrap();
+
+        // Two rows over two columns, a measured speed of 1.
+        let speed = staff.state().0[1][0];
+        assert!(speed > 0.5 && speed <= 1.0, "{}", speed);
+    }
+
+    #[test]
+    fn test_statuses_are_final_at_page_end() {
+        let height = 10;
+        let mut buffer = vec![255; 20 * height];
+        for c in 0..20 {
+            buffer[c * height + 2] = 0;
+        }
+        for c in 0..16 {
+            buffer[c * height + 5] = 0;
+        }
+        for c in 0..10 {
+            buffer[c * height + 8] = 0;
+        }
+        let config = TrackerConfig {
+            tolerances: Tolerances { max_gap: 6, ..Tolerances::default() },
+            ..TrackerConfig::default()
+        };
+
+        let staves = detect_staves_with(buffer, height, &config).unwrap();
+
+        let statuses = staves.iter().map(|s| (s.end(), s.status())).collect::<Vec<(usize, TrackStatus)>>();
+        assert_eq!(statuses, vec![
+            (20, TrackStatus::Confirmed),
+            (16, TrackStatus::Coasting),
+            (10, TrackStatus::Dead)
+        ]);
+    }
+
+    #[test]
+    fn test_smooth_slanted_line() {
+        let height = 20;
+        let mut buffer = vec![255; 40 * height];
+        for c in 0..40 {
+            if c == 20 || c == 21 {continue;}
+            buffer[c * height + 4 + c / 4] = 0;
+        }
+
+        let staves = detect_staves(buffer, height).unwrap();
+        assert_eq!(staves.len(), 1);
+
+        let smoothed = staves[0].smoothed();
+        assert_eq!(smoothed.len(), 40);
+        assert_eq!(smoothed.iter().map(|(y, _)| *y).collect::<Vec<usize>>(), (1..=40).collect::<Vec<usize>>());
+
+        let filtered = staves[0].buffer
+            .iter()
+            .zip(&staves[0].filtered)
+            .map(|((_, y), (x, _))| (*y, x.0[0][0]))
+            .collect::<Vec<(usize, f32)>>();
+        assert_eq!(filtered[..8].iter().map(|(y, _)| *y).collect::<Vec<usize>>(), (1..=8).collect::<Vec<usize>>());
+
+        let truth = |y: usize| 5.5 + (y - 1) as f32 / 4.0 - 0.375;
+        let error = |centres: &[(usize, f32)]| centres[..8].iter().map(|(y, x)| (x - truth(*y)).abs()).sum::<f32>();
+        assert!(error(smoothed) < error(&filtered), "{} {}", error(smoothed), error(&filtered));
+        assert!(smoothed.iter().all(|(y, x)| (x - truth(*y)).abs() < 1.0));
+    }
+
+    fn broken_line(height: usize, width: usize, hole: std::ops::Range<usize>, row: impl Fn(usize) -> usize) -> Vec<u8> {
+        let mut buffer = vec![255; width * height];
+        for c in (0..width).filter(|c| !hole.contains(c)) {
+            buffer[c * height + row(c)] = 0;
+            buffer[c * height + row(c) + 1] = 0;
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_merge_fragments_on_noisy_page() {
+        let config = crate::synthetic::PageConfig { width: 800, height: 600, systems: 4, noise: 0.01, ..Default::default() };
+        let page = crate::synthetic::generate(&config);
+        let buffer = crate::to_column_major(&page.image);
+        let config = crate::analysis::analyse(&buffer, 600).unwrap().tracker_config();
+
+        let tracks = track_columns(&buffer, 600, 0, &config).unwrap();
+        let count = tracks.len();
+
+        // Thousands of noise tracks are left out before merging, instead of being compared pairwise.
+        let confirmed = confirmed_staves(tracks, &config);
+        assert!(count > 1000, "{}", count);
+        assert_eq!(confirmed.len(), 20);
+
+        let staves = finish_staves(confirmed, &config).unwrap();
+        assert_eq!(staves.len(), 20);
+    }
+
+    #[test]
+    fn test_merge_fragments_across_wide_gap() {
+        let buffer = broken_line(20, 80, 20..50, |c| 4 + c / 10);
+        let broken = TrackerConfig {
+            tolerances: Tolerances { max_gap: 5, ..Tolerances::default() },
+            merge_gate: 0.0,
+            ..TrackerConfig::default()
+        };
+        assert_eq!(detect_staves_with(buffer.clone(), 20, &broken).unwrap().len(), 2);
+
+        let config = TrackerConfig { merge_gate: 3.0, ..broken };
+        let staves = detect_staves_with(buffer, 20, &config).unwrap();
+
+        assert_eq!(staves.len(), 1);
+        assert_eq!((staves[0].start(), staves[0].end()), (1, 80));
+        assert_eq!(staves[0].gaps(), &[(21, 50)]);
+        assert_eq!(staves[0].buffer.len(), 50);
+        assert_eq!(staves[0].smoothed().len(), 80);
+    }
+
+    #[test]
+    fn test_merge_fragments_keep_distant_lines_apart() {
+        let mut buffer = broken_line(30, 80, 40..80, |_| 4);
+        let second = broken_line(30, 80, 0..50, |_| 14);
+        buffer.iter_mut().zip(second).for_each(|(a, b)| *a = (*a).min(b));
+        let config = TrackerConfig {
+            tolerances: Tolerances { max_gap: 5, ..Tolerances::default() },
+            ..TrackerConfig::default()
+        };
+
+        let staves = detect_staves_with(buffer, 30, &config).unwrap();
+
+        assert_eq!(staves.len(), 2);
+        assert!(staves.iter().all(|s| s.gaps().is_empty()));
+    }
+
+    #[test]
+    fn test_detect_staves_in_strips_stitch_lines() {
+        let mut buffer = broken_line(40, 300, 0..0, |c| 4 + c / 20);
+        let second = broken_line(40, 300, 90..130, |_| 30);
+        buffer.iter_mut().zip(second).for_each(|(a, b)| *a = (*a).min(b));
+        let strips = Strips { width: 60, overlap: 15 };
+        let config = TrackerConfig {
+            tolerances: Tolerances { max_gap: 5, ..Tolerances::default() },
+            ..TrackerConfig::default()
+        };
+
+        let whole = detect_staves_with(buffer.clone(), 40, &config).unwrap();
+        let staves = detect_staves_in_strips(buffer, 40, &config, &strips).unwrap();
+
+        assert_eq!(staves.len(), 2);
+        for (a, b) in staves.iter().zip(whole.iter()) {
+            assert_eq!(a.buffer, b.buffer);
+            assert_eq!(a.gaps(), b.gaps());
+        }
+        assert!(staves.iter().any(|s| s.gaps() == [(91, 130)]));
+    }
+
+    #[test]
+    fn test_detect_staves_in_strips_keep_narrow_page_whole() {
+        let buffer = broken_line(20, 80, 0..0, |_| 4);
+        let staves = detect_staves_in_strips(buffer, 20, &TrackerConfig::default(), &Strips { width: 40, overlap: 10 }).unwrap();
+
+        assert_eq!(staves.len(), 1);
+        assert_eq!((staves[0].start(), staves[0].end()), (1, 80));
+        assert!(matches!(
+            detect_staves_in_strips(vec![0; 5], 2, &TrackerConfig::default(), &Strips::default()),
+            Err(Error::InvalidDimensions { .. })
+        ));
+    }
+}
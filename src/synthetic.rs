@@ -0,0 +1,361 @@
+use std::path::Path;
+
+use image::{GrayImage, Luma};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/**
+width, height : The page dimensions in pixel.
+systems : Number of staves, spread evenly from the top to the bottom of the page.
+lines : Number of lines of each staff.
+spacing : Distance in pixel between the centres of two consecutive lines.
+thickness : Line thickness in pixel.
+skew : Slope of the lines in degree, going down to the right when positive.
+curvature : Vertical bow in pixel of every line, at the middle of the page.
+gaps : Number of holes of `gap_width` columns drawn at random in every line.
+slurs : Number of slurs crossing every staff from above to below, or the other way round.
+noise : Probability of every pixel to be flipped.
+seed : Seed of the random generator, the same config always gives the same page.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageConfig {
+    pub width: usize,
+    pub height: usize,
+    pub systems: usize,
+    pub lines: usize,
+    pub spacing: f32,
+    pub thickness: f32,
+    pub skew: f32,
+    pub curvature: f32,
+    pub gaps: usize,
+    pub gap_width: usize,
+    pub slurs: usize,
+    pub noise: f64,
+    pub seed: u64
+}
+
+impl Default for PageConfig {
+    fn default() -> PageConfig {
+        PageConfig {
+            width: 800,
+            height: 600,
+            systems: 3,
+            lines: 5,
+            spacing: 12.0,
+            thickness: 2.0,
+            skew: 0.0,
+            curvature: 0.0,
+            gaps: 0,
+            gap_width: 20,
+            slurs: 0,
+            noise: 0.0,
+            seed: 0
+        }
+    }
+}
+
+/**
+The exact staff lines of a generated page.
+
+width, height : The page dimensions in pixel.
+lines : Every staff line, from the top staff down and from its top line down.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroundTruth {
+    pub width: usize,
+    pub height: usize,
+    pub lines: Vec<TruthLine>
+}
+
+/**
+system : The index of the staff on the page.
+line : The index of the line in its staff.
+thickness : The drawn thickness in pixel.
+points : The mean centre of the drawn pixels of every column as `[column, centre]`, 1-based columns
+and rows with the pixel-centre convention of `Staff::centres`. Columns of the gaps are left out.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TruthLine {
+    pub system: usize,
+    pub line: usize,
+    pub thickness: f32,
+    pub points: Vec<(usize, f32)>
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntheticPage {
+    pub image: GrayImage,
+    pub truth: GroundTruth
+}
+
+impl GroundTruth {
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<GroundTruth> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /**
+    Reads the ground truth saved next to a page by `SyntheticPage::save`.
+    */
+    pub fn open<P: AsRef<Path>>(page_path: P) -> Result<GroundTruth> {
+        GroundTruth::from_json(&std::fs::read_to_string(page_path.as_ref().with_extension("json"))?)
+    }
+
+}
+
+impl SyntheticPage {
+
+    /**
+    Saves the page image to `path` and its ground truth next to it, with the json extension.
+    */
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.image.save(&path)?;
+        std::fs::write(path.as_ref().with_extension("json"), self.truth.to_json()?)?;
+        Ok(())
+    }
+
+}
+
+impl PageConfig {
+
+    fn margin(&self) -> f32 {
+        2.0 * self.spacing
+    }
+
+    /**
+    Centre of a line at a 1-based column, in 1-based rows.
+    */
+    fn centre(&self, system: usize, line: usize, column: f32) -> f32 {
+        let (left, right) = (self.margin(), self.width as f32 - self.margin());
+        let u = (column - left) / (right - left).max(1.0);
+        let staff_height = (self.lines.max(1) - 1) as f32 * self.spacing;
+        let top = self.height as f32 * (system as f32 + 0.5) / self.systems as f32 - staff_height / 2.0;
+
+        top + line as f32 * self.spacing
+            + self.skew.to_radians().tan() * (column - self.width as f32 / 2.0)
+            + self.curvature * 4.0 * u * (1.0 - u)
+    }
+
+}
+
+fn put_black(img: &mut GrayImage, column: i64, row: i64) {
+    if column >= 0 && row >= 0 && (column as u32) < img.width() && (row as u32) < img.height() {
+        img.put_pixel(column as u32, row as u32, Luma([0]));
+    }
+}
+
+/**
+Blackens the pixels of a column whose centre lies within half a thickness of `centre`, in 1-based rows.
+Returns the mean centre of the blackened pixels, `centre` when none lies on the page.
+*/
+fn draw_column(img: &mut GrayImage, column: usize, centre: f32, thickness: f32) -> f32 {
+    let first = ((centre - thickness / 2.0 - 1.5).ceil() as i64).max(0);
+    let last = ((centre + thickness / 2.0 - 1.5).ceil() as i64).min(img.height() as i64);
+    for row in first..last {
+        put_black(img, column as i64 - 1, row);
+    }
+    match first < last {
+        true => (first + last) as f32 / 2.0 + 1.0,
+        false => centre
+    }
+}
+
+/**
+A parabolic arc from above a staff to below it, or the other way round, drawn with square stamps.
+*/
+fn draw_slur(img: &mut GrayImage, config: &PageConfig, system: usize, rng: &mut StdRng) {
+    let length = rng.gen_range(4.0, 10.0) * config.spacing;
+    let x0 = rng.gen_range(config.margin(), (config.width as f32 - config.margin() - length).max(config.margin() + 1.0));
+    let x1 = x0 + length;
+    let top = |x| config.centre(system, 0, x) - 2.0 * config.spacing;
+    let bottom = |x| config.centre(system, config.lines.max(1) - 1, x) + 2.0 * config.spacing;
+    let (y0, y1) = match rng.gen_bool(0.5) {
+        true => (top(x0), bottom(x1)),
+        false => (bottom(x0), top(x1))
+    };
+    let bulge = rng.gen_range(-2.0, 2.0) * config.spacing;
+
+    let steps = (4.0 * (length + (y1 - y0).abs())) as usize;
+    let half = (config.thickness / 2.0).max(0.5);
+    for step in 0..=steps {
+        let u = step as f32 / steps as f32;
+        let x = x0 + length * u;
+        let y = y0 + (y1 - y0) * u - bulge * 4.0 * u * (1.0 - u);
+        for column in (x - half).round() as i64..(x + half).round() as i64 {
+            for row in (y - half - 1.5).ceil() as i64..(y + half - 1.5).ceil() as i64 {
+                put_black(img, column - 1, row);
+            }
+        }
+    }
+}
+
+/**
+Renders a page of staves with their exact line centres, see `PageConfig`.
+*/
+pub fn generate(config: &PageConfig) -> SyntheticPage {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut image = GrayImage::from_pixel(config.width as u32, config.height as u32, Luma([255]));
+    let mut lines = Vec::new();
+
+    let left = config.margin().ceil() as usize + 1;
+    let right = (config.width as f32 - config.margin()) as usize;
+
+    for system in 0..config.systems {
+        for line in 0..config.lines {
+            let holes = (0..config.gaps)
+                .map(|_| rng.gen_range(left, right.saturating_sub(config.gap_width).max(left + 1)))
+                .collect::<Vec<usize>>();
+
+            let points = (left..=right)
+                .filter(|column| !holes.iter().any(|h| (*h..*h + config.gap_width).contains(column)))
+                .map(|column| (column, config.centre(system, line, column as f32)))
+                .filter(|(_, centre)| *centre > 0.0 && *centre < config.height as f32)
+                .map(|(column, centre)| (column, draw_column(&mut image, column, centre, config.thickness)))
+                .collect::<Vec<(usize, f32)>>();
+
+            lines.push(TruthLine { system, line, thickness: config.thickness, points });
+        }
+
+        for _ in 0..config.slurs {
+            draw_slur(&mut image, config, system, &mut rng);
+        }
+    }
+
+    if config.noise > 0.0 {
+        for pixel in image.pixels_mut() {
+            if rng.gen_bool(config.noise) {
+                pixel.0[0] = 255 - pixel.0[0];
+            }
+        }
+    }
+
+    SyntheticPage {
+        image,
+        truth: GroundTruth { width: config.width, height: config.height, lines }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::{ScanConfig, Scanner};
+
+    #[test]
+    fn test_generate_is_reproducible() {
+        let config = PageConfig { gaps: 2, slurs: 1, noise: 0.01, seed: 7, ..PageConfig::default() };
+
+        let a = generate(&config);
+        let b = generate(&config);
+        let c = generate(&PageConfig { seed: 8, ..config });
+
+        assert_eq!(a.image, b.image);
+        assert_eq!(a.truth, b.truth);
+        assert_ne!(a.image, c.image);
+    }
+
+    #[test]
+    fn test_truth_is_the_mean_of_drawn_pixels() {
+        let config = PageConfig { systems: 1, curvature: 5.0, skew: 1.0, ..PageConfig::default() };
+        let page = generate(&config);
+
+        assert_eq!(page.truth.lines.len(), 5);
+        for line in &page.truth.lines {
+            for (column, centre) in line.points.iter().step_by(37) {
+                let rows = (0..config.height as u32)
+                    .filter(|r| (*r as f32 + 1.5 - centre).abs() < config.spacing / 2.0)
+                    .filter(|r| page.image.get_pixel(*column as u32 - 1, *r).0[0] == 0)
+                    .collect::<Vec<u32>>();
+                let mean = rows.iter().map(|r| *r as f32 + 1.5).sum::<f32>() / rows.len() as f32;
+
+                assert_eq!(rows.len(), 2);
+                assert!((mean - centre).abs() < 1e-4, "{} {}", mean, centre);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gaps_are_left_out_of_truth() {
+        let config = PageConfig { systems: 1, lines: 1, gaps: 1, gap_width: 30, ..PageConfig::default() };
+        let page = generate(&config);
+        let points = &page.truth.lines[0].points;
+
+        let missing = points.windows(2).map(|w| w[1].0 - w[0].0 - 1).sum::<usize>();
+        assert_eq!(missing, 30);
+    }
+
+    #[test]
+    fn test_truth_json_round_trip() {
+        let page = generate(&PageConfig { systems: 1, ..PageConfig::default() });
+        let path = std::env::temp_dir().join(format!("rustscanscore_{}_synthetic.png", std::process::id()));
+
+        page.save(&path).unwrap();
+        let truth = GroundTruth::open(&path).unwrap();
+        let image = crate::input::open_luma(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert_eq!(image, page.image);
+        assert_eq!(truth.lines.len(), page.truth.lines.len());
+        assert_eq!(truth.lines[0].points.len(), page.truth.lines[0].points.len());
+    }
+
+    #[test]
+    fn test_detect_staves_on_generated_pages() {
+        let scanner = Scanner::new(ScanConfig { deskew: false, ..ScanConfig::default() });
+
+        for seed in 0..12 {
+            let config = PageConfig {
+                systems: 2 + seed as usize % 3,
+                spacing: 10.0 + (seed % 4) as f32 * 2.0,
+                thickness: 1.0 + (seed % 3) as f32,
+                curvature: (seed % 5) as f32,
+                skew: 0.3 * (seed % 3) as f32 - 0.3,
+                gaps: (seed % 2) as usize,
+                slurs: (seed % 3) as usize,
+                seed,
+                width: 600,
+                height: 400,
+                ..PageConfig::default()
+            };
+            let page = generate(&config);
+            let result = scanner.scan_image(&image::DynamicImage::ImageLuma8(page.image)).unwrap();
+
+            let mut centres = HashMap::<usize, Vec<f32>>::new();
+            for (column, x) in result.staves.iter().flat_map(|staff| staff.centres()) {
+                centres.entry(column).or_default().push(x);
+            }
+            for line in &page.truth.lines {
+                // Fragments of a line broken by a slur count as found.
+                let covered = line.points
+                    .iter()
+                    .filter(|(column, centre)| centres.get(column).is_some_and(|xs| xs.iter().any(|x| (x - centre).abs() <= 1.0)))
+                    .count();
+                assert!(covered as f32 >= 0.9 * line.points.len() as f32, "seed {} misses line {:?}", seed, (line.system, line.line));
+            }
+        }
+    }
+
+    #[test]
+    fn test_clean_page_tracks_match_truth() {
+        // Staves centred on half rows, with lines of an even thickness.
+        let config = PageConfig { height: 900, systems: 4, ..PageConfig::default() };
+        let page = generate(&config);
+        let scanner = Scanner::new(ScanConfig { deskew: false, ..ScanConfig::default() });
+        let result = scanner.scan_image(&image::DynamicImage::ImageLuma8(page.image)).unwrap();
+
+        let evaluation = crate::evaluation::evaluate_staves(&page.truth, &result.staves, 1.0);
+
+        assert_eq!(evaluation.recall(), 1.0);
+        assert!(evaluation.mean_error() < 0.05, "{}", evaluation.mean_error());
+    }
+}
@@ -0,0 +1,443 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::staves::Staff;
+
+/**
+A tracked line kept as part of a staff.
+
+track : Index of the line in the `Staff` list given to `group_staves`.
+position : Mean pixel centre of the line.
+thickness : Mean run height of the line.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LineTrack {
+    pub track: usize,
+    pub start: usize,
+    pub end: usize,
+    pub position: f32,
+    pub thickness: f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaffSystem {
+    pub lines: [LineTrack; 5],
+    pub spacing: f32,
+    pub thickness: f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tablature {
+    pub lines: [LineTrack; 6],
+    pub spacing: f32,
+    pub thickness: f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StaffGroup {
+    Staff(StaffSystem),
+    Percussion(LineTrack),
+    Tablature(Tablature)
+}
+
+impl StaffGroup {
+
+    pub fn lines(&self) -> &[LineTrack] {
+        match self {
+            StaffGroup::Staff(s) => &s.lines,
+            StaffGroup::Percussion(l) => std::slice::from_ref(l),
+            StaffGroup::Tablature(t) => &t.lines
+        }
+    }
+
+}
+
+// A line must cover at least this ratio of the longest track.
+const MIN_LENGTH_RATIO: f32 = 0.5;
+// A line can not be thicker than this ratio of the median thickness.
+const MAX_THICKNESS_RATIO: f32 = 2.0;
+// Two lines of a staff must share this ratio of the shortest one.
+const MIN_OVERLAP_RATIO: f32 = 0.5;
+// Relative tolerance between two consecutive spacings of a staff.
+const SPACING_TOLERANCE: f32 = 0.2;
+// Maximum RMS distance in pixel between a line and its linear fit.
+const MAX_RESIDUAL: f32 = 1.5;
+// Largest increase of the irregularity of a chain for its sixth line to be kept, see `irregularity`.
+const MAX_SIXTH_LINE_IRREGULARITY: f32 = 0.05;
+
+#[derive(Debug)]
+struct Candidate {
+    line: LineTrack,
+    centres: Vec<(usize, f32)>
+}
+
+impl Candidate {
+
+    fn new(track: usize, staff: &Staff) -> Candidate {
+        Candidate {
+            line: LineTrack {
+                track,
+                start: staff.start(),
+                end: staff.end(),
+                position: staff.position(),
+                thickness: staff.thickness()
+            },
+            centres: staff.centres()
+        }
+    }
+
+    fn length(&self) -> usize {
+        self.line.end - self.line.start + 1
+    }
+
+    fn residual(&self) -> f32 {
+        let n = self.centres.len() as f32;
+        let mean_y = self.centres.iter().map(|(y, _)| *y as f32).sum::<f32>() / n;
+        let mean_x = self.line.position;
+        let (cov, var) = self.centres
+            .iter()
+            .fold((0.0, 0.0), |(cov, var), (y, x)| (
+                cov + (*y as f32 - mean_y) * (x - mean_x),
+                var + (*y as f32 - mean_y).powi(2)
+            ));
+        let slope = if var > 0.0 {cov / var} else {0.0};
+
+        (
+            self.centres
+                .iter()
+                .map(|(y, x)| (x - mean_x - slope * (*y as f32 - mean_y)).powi(2))
+                .sum::<f32>()
+            / n
+        ).sqrt()
+    }
+
+    fn overlap(&self, other: &Candidate) -> bool {
+        let start = self.line.start.max(other.line.start);
+        let end = self.line.end.min(other.line.end);
+        end >= start
+            && (end - start + 1) as f32 >= MIN_OVERLAP_RATIO * self.length().min(other.length()) as f32
+    }
+
+    /**
+    Mean vertical distance to another candidate over their common columns.
+    */
+    fn offset(&self, other: &Candidate) -> Option<f32> {
+        let mut i = 0;
+        let mut sum = 0.0;
+        let mut count = 0;
+        for (y, x) in &self.centres {
+            while i < other.centres.len() && other.centres[i].0 < *y {i += 1;}
+            if i < other.centres.len() && other.centres[i].0 == *y {
+                sum += other.centres[i].1 - x;
+                count += 1;
+            }
+        }
+        match count {
+            0 => None,
+            _ => Some(sum / count as f32)
+        }
+    }
+
+}
+
+/**
+Clusters the tracks returned by `staves::detect_staves` into staves, from top to bottom.
+Tracks that are too short, too thick or curved are dropped, as well as
+groups of lines that do not form a 1, 5 or 6 lines staff.
+*/
+pub fn group_staves(staves: &[Staff]) -> Vec<StaffGroup> {
+    let mut candidates = select_candidates(staves);
+    candidates.sort_by(
+        |a, b|
+        a.line.position.partial_cmp(&b.line.position)
+        .unwrap_or(std::cmp::Ordering::Equal)
+    );
+
+    let spacing = estimate_spacing(&candidates);
+    debug!("Group {:?} candidate lines with spacing:{:?}", candidates.len(), spacing);
+
+    let mut used = vec![false; candidates.len()];
+    let mut groups = Vec::new();
+
+    for first in 0..candidates.len() {
+        if used[first] {continue;}
+
+        let chain = build_chain(&candidates, &used, first, spacing);
+        let lines = chain.iter().map(|i| candidates[*i].line).collect::<Vec<LineTrack>>();
+
+        match lines.len() {
+            1 if isolated(&candidates, first, spacing) => groups.push(StaffGroup::Percussion(lines[0])),
+            5 | 6 => {
+                let spacing = chain
+                    .windows(2)
+                    .filter_map(|w| candidates[w[0]].offset(&candidates[w[1]]))
+                    .sum::<f32>() / (chain.len() - 1) as f32;
+                let thickness = lines.iter().map(|l| l.thickness).sum::<f32>() / lines.len() as f32;
+
+                if lines.len() == 5 {
+                    let mut array = [lines[0]; 5];
+                    array.copy_from_slice(&lines);
+                    groups.push(StaffGroup::Staff(StaffSystem { lines: array, spacing, thickness }));
+                } else {
+                    let mut array = [lines[0]; 6];
+                    array.copy_from_slice(&lines);
+                    groups.push(StaffGroup::Tablature(Tablature { lines: array, spacing, thickness }));
+                }
+            },
+            _ => {
+                debug!("Reject line {:?} starting a group of {:?} lines", lines[0], lines.len());
+                used[first] = true;
+                continue;
+            }
+        }
+
+        for i in chain {
+            used[i] = true;
+        }
+    }
+
+    groups
+}
+
+fn select_candidates(staves: &[Staff]) -> Vec<Candidate> {
+    let candidates = staves
+        .iter()
+        .enumerate()
+        .filter(|(_, staff)| !staff.buffer.is_empty())
+        .map(|(i, staff)| Candidate::new(i, staff))
+        .collect::<Vec<Candidate>>();
+
+    let max_length = candidates.iter().map(|c| c.length()).max().unwrap_or(0);
+
+    let long = candidates
+        .into_iter()
+        .filter(|c| c.length() as f32 >= MIN_LENGTH_RATIO * max_length as f32)
+        .collect::<Vec<Candidate>>();
+
+    let thickness = median(long.iter().map(|c| c.line.thickness).collect()).unwrap_or(0.0);
+
+    long
+        .into_iter()
+        .filter(|c| {
+            let keep = c.line.thickness <= MAX_THICKNESS_RATIO * thickness
+                && c.residual() <= MAX_RESIDUAL.max(c.line.thickness);
+            if !keep {debug!("Reject spurious line {:?}", c.line);}
+            keep
+        })
+        .collect()
+}
+
+/**
+Median distance between each candidate and the closest overlapping one below.
+*/
+fn estimate_spacing(candidates: &[Candidate]) -> Option<f32> {
+    let distances = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)|
+            candidates[i + 1..]
+                .iter()
+                .filter(|other| c.overlap(other))
+                .filter_map(|other| c.offset(other))
+                .find(|d| *d > c.line.thickness)
+        )
+        .collect();
+
+    median(distances)
+}
+
+/**
+Chains up to 6 evenly spaced lines from `first` downwards. Of 6 chained lines, the 5 most regular ones are kept
+unless the sixth one fits the others as well, so that a ledger line or an underline at the staff spacing
+does not turn a staff into a tablature.
+*/
+fn build_chain(candidates: &[Candidate], used: &[bool], first: usize, spacing: Option<f32>) -> Vec<usize> {
+    let mut chain = vec![first];
+    let mut chain_spacing: Option<f32> = None;
+
+    while chain.len() < 6 {
+        let last = &candidates[*chain.last().unwrap()];
+
+        let next = (chain.last().unwrap() + 1..candidates.len())
+            .filter(|i| !used[*i] && last.overlap(&candidates[*i]))
+            .filter_map(|i| last.offset(&candidates[i]).map(|d| (i, d)))
+            .find(|(_, d)| *d > last.line.thickness);
+
+        let accepted = match (next, chain_spacing, spacing) {
+            (Some((_, d)), Some(s), _) => (d - s).abs() <= SPACING_TOLERANCE * s,
+            (Some((_, d)), None, Some(s)) => d <= 2.0 * s,
+            _ => false
+        };
+        if !accepted {break;}
+
+        let (i, d) = next.unwrap();
+        chain_spacing.get_or_insert(d);
+        chain.push(i);
+    }
+
+    if chain.len() == 6 {
+        let five = [&chain[..5], &chain[1..]]
+            .iter()
+            .map(|five| (*five, irregularity(candidates, five)))
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap();
+
+        if irregularity(candidates, &chain) > five.1 + MAX_SIXTH_LINE_IRREGULARITY {
+            debug!("Keep 5 of the 6 lines {:?}", chain);
+            return five.0.to_vec();
+        }
+    }
+
+    chain
+}
+
+/**
+Sum of the relative standard deviations of the spacings and of the lengths of the lines of a chain,
+0 for evenly spaced lines of the same length.
+*/
+fn irregularity(candidates: &[Candidate], chain: &[usize]) -> f32 {
+    let spacings = chain
+        .windows(2)
+        .filter_map(|w| candidates[w[0]].offset(&candidates[w[1]]))
+        .collect::<Vec<f32>>();
+    let lengths = chain.iter().map(|i| candidates[*i].length() as f32).collect::<Vec<f32>>();
+
+    relative_deviation(&spacings) + relative_deviation(&lengths)
+}
+
+fn relative_deviation(values: &[f32]) -> f32 {
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+    variance.sqrt() / mean
+}
+
+/**
+A single line is only a percussion staff when no other line lies in its neighbourhood.
+*/
+fn isolated(candidates: &[Candidate], i: usize, spacing: Option<f32>) -> bool {
+    let spacing = match spacing {
+        Some(s) => s,
+        None => return true
+    };
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(j, other)| *j != i && candidates[i].overlap(other))
+        .filter_map(|(_, other)| candidates[i].offset(other))
+        .all(|d| d.abs() > 2.0 * spacing)
+}
+
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values.get(values.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::staves::detect_staves;
+
+    fn draw_lines(width: usize, height: usize, lines: &[(usize, usize, usize, usize)]) -> Vec<u8> {
+        let mut buffer = vec![255; width * height];
+        for (row, thickness, from, to) in lines {
+            for c in *from..*to {
+                for r in *row..*row + *thickness {
+                    buffer[c * height + r] = 0;
+                }
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_group_five_lines_into_staff() {
+        let height = 60;
+        let lines = (0..5).map(|i| (10 + i * 6, 1, 0, 100)).collect::<Vec<_>>();
+        let staves = detect_staves(draw_lines(100, height, &lines), height).unwrap();
+
+        let groups = group_staves(&staves);
+
+        assert_eq!(groups.len(), 1);
+        match &groups[0] {
+            StaffGroup::Staff(s) => {
+                assert!((s.spacing - 6.0).abs() < 0.01);
+                assert!((s.thickness - 1.0).abs() < 0.01);
+                assert!((s.lines[0].position - 11.5).abs() < 0.01);
+                assert!((s.lines[4].position - 35.5).abs() < 0.01);
+            },
+            g => panic!("Unexpected group {:?}", g)
+        }
+    }
+
+    #[test]
+    fn test_reject_underline_and_beam() {
+        let height = 80;
+        let mut lines = (0..5).map(|i| (10 + i * 6, 1, 0, 100)).collect::<Vec<_>>();
+        lines.push((60, 1, 10, 30));
+        lines.push((70, 4, 0, 100));
+        let staves = detect_staves(draw_lines(100, height, &lines), height).unwrap();
+
+        let groups = group_staves(&staves);
+
+        assert_eq!(groups.len(), 1);
+        assert!(matches!(groups[0], StaffGroup::Staff(_)));
+    }
+
+    #[test]
+    fn test_report_percussion_and_tablature() {
+        let height = 120;
+        let mut lines = vec![(8, 1, 0, 100)];
+        lines.extend((0..6).map(|i| (40 + i * 8, 1, 0, 100)));
+        let staves = detect_staves(draw_lines(100, height, &lines), height).unwrap();
+
+        let groups = group_staves(&staves);
+
+        assert_eq!(groups.len(), 2);
+        assert!(matches!(groups[0], StaffGroup::Percussion(_)));
+        match &groups[1] {
+            StaffGroup::Tablature(t) => assert!((t.spacing - 8.0).abs() < 0.01),
+            g => panic!("Unexpected group {:?}", g)
+        }
+    }
+
+    #[test]
+    fn test_staff_with_extra_line_at_staff_spacing() {
+        let height = 80;
+        let staff = (0..5).map(|i| (16 + i * 6, 1, 0, 100)).collect::<Vec<_>>();
+
+        for extra in [(10, 1, 0, 60), (46, 1, 30, 100)] {
+            let mut lines = staff.clone();
+            lines.push(extra);
+            let staves = detect_staves(draw_lines(100, height, &lines), height).unwrap();
+
+            let groups = group_staves(&staves);
+
+            assert_eq!(groups.len(), 1, "{:?}", extra);
+            match &groups[0] {
+                StaffGroup::Staff(s) => {
+                    assert!((s.spacing - 6.0).abs() < 0.01);
+                    assert!((s.lines[0].position - 17.5).abs() < 0.01);
+                    assert!((s.lines[4].position - 41.5).abs() < 0.01);
+                },
+                g => panic!("Unexpected group {:?} with extra line {:?}", g, extra)
+            }
+        }
+    }
+
+    #[test]
+    fn test_reject_incomplete_staff() {
+        let height = 60;
+        let lines = (0..3).map(|i| (10 + i * 6, 1, 0, 100)).collect::<Vec<_>>();
+        let staves = detect_staves(draw_lines(100, height, &lines), height).unwrap();
+
+        assert_eq!(group_staves(&staves), Vec::new());
+    }
+
+    #[test]
+    fn test_group_empty() {
+        assert_eq!(group_staves(&[]), Vec::new());
+    }
+}